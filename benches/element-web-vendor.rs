@@ -34,6 +34,38 @@ fn blake3_balanced_rayon(data: &[u8]) -> ::blake3_balanced::Hash {
     hasher.finalize()
 }
 
+#[cfg(feature = "rayon")]
+fn blake3_balanced_rayon_min_len<const MIN_LEN: usize>(data: &[u8]) -> ::blake3_balanced::Hash {
+    let mut hasher = ::blake3_balanced::Hasher::new();
+    hasher.update_with_join::<::blake3_balanced::RayonJoin<MIN_LEN>>(data);
+    hasher.finalize()
+}
+
+// Compare thresholds for RayonJoin, both on the whole input and on a prefix
+// short enough that task overhead matters. This is how the default
+// RAYON_DEFAULT_MIN_LEN was chosen.
+#[cfg(feature = "rayon")]
+pub fn bench_rayon_min_len(c: &mut Criterion) {
+    let mut group = c.benchmark_group("rayon-min-len");
+    group.measurement_time(Duration::from_secs(6));
+    for &len in &[256 * 1024, INPUT.len()] {
+        let input = &INPUT[..len];
+        group.bench_function(format!("{} bytes, min_len=0", len), |b| {
+            b.iter(|| blake3_balanced_rayon_min_len::<0>(black_box(input)))
+        });
+        group.bench_function(format!("{} bytes, min_len=32KiB", len), |b| {
+            b.iter(|| blake3_balanced_rayon_min_len::<{ 32 * 1024 }>(black_box(input)))
+        });
+        group.bench_function(format!("{} bytes, min_len=128KiB", len), |b| {
+            b.iter(|| blake3_balanced_rayon_min_len::<{ 128 * 1024 }>(black_box(input)))
+        });
+        group.bench_function(format!("{} bytes, min_len=512KiB", len), |b| {
+            b.iter(|| blake3_balanced_rayon_min_len::<{ 512 * 1024 }>(black_box(input)))
+        });
+    }
+    group.finish();
+}
+
 pub fn bench_element_web_vendor(c: &mut Criterion) {
     let mut group = c.benchmark_group("element-web-vendor");
     group.measurement_time(Duration::from_secs(12));
@@ -54,5 +86,8 @@ pub fn bench_element_web_vendor(c: &mut Criterion) {
     group.finish();
 }
 
+#[cfg(feature = "rayon")]
+criterion_group!(benches, bench_element_web_vendor, bench_rayon_min_len);
+#[cfg(not(feature = "rayon"))]
 criterion_group!(benches, bench_element_web_vendor);
 criterion_main!(benches);
@@ -0,0 +1,308 @@
+// An AVX2 implementation of hash_many(), which hashes eight inputs at once.
+// AVX2 doesn't help the single-block compression function, so that and any
+// leftover inputs go to the SSE4.1 implementation. Everything here is unsafe
+// to call unless the CPU supports both AVX2 and SSE4.1, which
+// platform::Platform checks at runtime before dispatching.
+#![allow(unsafe_code)]
+
+use core::arch::x86_64::*;
+
+use crate::compress::MSG_SCHEDULE;
+use crate::platform::{array_ref, array_ref_mut};
+use crate::{counter_high, counter_low, CVWords, IncrementCounter, BLOCK_LEN, IV, OUT_LEN};
+
+pub const DEGREE: usize = 8;
+
+#[inline(always)]
+unsafe fn loadu(src: *const u8) -> __m256i {
+    // This is an unaligned load, so the pointer cast is allowed.
+    _mm256_loadu_si256(src as *const __m256i)
+}
+
+#[inline(always)]
+unsafe fn storeu(src: __m256i, dest: *mut u8) {
+    // This is an unaligned store, so the pointer cast is allowed.
+    _mm256_storeu_si256(dest as *mut __m256i, src)
+}
+
+#[inline(always)]
+unsafe fn add(a: __m256i, b: __m256i) -> __m256i {
+    _mm256_add_epi32(a, b)
+}
+
+#[inline(always)]
+unsafe fn xor(a: __m256i, b: __m256i) -> __m256i {
+    _mm256_xor_si256(a, b)
+}
+
+#[inline(always)]
+unsafe fn set1(x: u32) -> __m256i {
+    _mm256_set1_epi32(x as i32)
+}
+
+#[inline(always)]
+#[allow(clippy::too_many_arguments)]
+unsafe fn set8(a: u32, b: u32, c: u32, d: u32, e: u32, f: u32, g: u32, h: u32) -> __m256i {
+    _mm256_setr_epi32(
+        a as i32, b as i32, c as i32, d as i32, e as i32, f as i32, g as i32, h as i32,
+    )
+}
+
+// Rotations by whole bytes are a single byte shuffle. The others take two
+// shifts and an OR. The shuffle masks repeat for each 128-bit half, because
+// _mm256_shuffle_epi8 doesn't cross between them.
+#[inline(always)]
+unsafe fn rot16(a: __m256i) -> __m256i {
+    _mm256_shuffle_epi8(
+        a,
+        _mm256_setr_epi8(
+            2, 3, 0, 1, 6, 7, 4, 5, 10, 11, 8, 9, 14, 15, 12, 13, //
+            2, 3, 0, 1, 6, 7, 4, 5, 10, 11, 8, 9, 14, 15, 12, 13,
+        ),
+    )
+}
+
+#[inline(always)]
+unsafe fn rot12(a: __m256i) -> __m256i {
+    _mm256_or_si256(_mm256_srli_epi32(a, 12), _mm256_slli_epi32(a, 32 - 12))
+}
+
+#[inline(always)]
+unsafe fn rot8(a: __m256i) -> __m256i {
+    _mm256_shuffle_epi8(
+        a,
+        _mm256_setr_epi8(
+            1, 2, 3, 0, 5, 6, 7, 4, 9, 10, 11, 8, 13, 14, 15, 12, //
+            1, 2, 3, 0, 5, 6, 7, 4, 9, 10, 11, 8, 13, 14, 15, 12,
+        ),
+    )
+}
+
+#[inline(always)]
+unsafe fn rot7(a: __m256i) -> __m256i {
+    _mm256_or_si256(_mm256_srli_epi32(a, 7), _mm256_slli_epi32(a, 32 - 7))
+}
+
+// The transposed G function, where each vector holds one word of the state
+// for each of the DEGREE inputs.
+#[inline(always)]
+unsafe fn g(v: &mut [__m256i; 16], a: usize, b: usize, c: usize, d: usize, x: __m256i, y: __m256i) {
+    v[a] = add(add(v[a], v[b]), x);
+    v[d] = rot16(xor(v[d], v[a]));
+    v[c] = add(v[c], v[d]);
+    v[b] = rot12(xor(v[b], v[c]));
+    v[a] = add(add(v[a], v[b]), y);
+    v[d] = rot8(xor(v[d], v[a]));
+    v[c] = add(v[c], v[d]);
+    v[b] = rot7(xor(v[b], v[c]));
+}
+
+#[inline(always)]
+unsafe fn round(v: &mut [__m256i; 16], m: &[__m256i; 16], round: usize) {
+    // Select the message schedule based on the round.
+    let s = MSG_SCHEDULE[round];
+
+    // Mix the columns.
+    g(v, 0, 4, 8, 12, m[s[0]], m[s[1]]);
+    g(v, 1, 5, 9, 13, m[s[2]], m[s[3]]);
+    g(v, 2, 6, 10, 14, m[s[4]], m[s[5]]);
+    g(v, 3, 7, 11, 15, m[s[6]], m[s[7]]);
+
+    // Mix the diagonals.
+    g(v, 0, 5, 10, 15, m[s[8]], m[s[9]]);
+    g(v, 1, 6, 11, 12, m[s[10]], m[s[11]]);
+    g(v, 2, 7, 8, 13, m[s[12]], m[s[13]]);
+    g(v, 3, 4, 9, 14, m[s[14]], m[s[15]]);
+}
+
+// Transpose an 8x8 matrix of words, so that vecs[i][j] becomes vecs[j][i].
+// The unpack instructions work within each 128-bit half, so the last step
+// swaps halves across vectors.
+#[inline(always)]
+unsafe fn transpose_vecs(vecs: &mut [__m256i; DEGREE]) {
+    let ab_0145 = _mm256_unpacklo_epi32(vecs[0], vecs[1]);
+    let ab_2367 = _mm256_unpackhi_epi32(vecs[0], vecs[1]);
+    let cd_0145 = _mm256_unpacklo_epi32(vecs[2], vecs[3]);
+    let cd_2367 = _mm256_unpackhi_epi32(vecs[2], vecs[3]);
+    let ef_0145 = _mm256_unpacklo_epi32(vecs[4], vecs[5]);
+    let ef_2367 = _mm256_unpackhi_epi32(vecs[4], vecs[5]);
+    let gh_0145 = _mm256_unpacklo_epi32(vecs[6], vecs[7]);
+    let gh_2367 = _mm256_unpackhi_epi32(vecs[6], vecs[7]);
+
+    let abcd_04 = _mm256_unpacklo_epi64(ab_0145, cd_0145);
+    let abcd_15 = _mm256_unpackhi_epi64(ab_0145, cd_0145);
+    let abcd_26 = _mm256_unpacklo_epi64(ab_2367, cd_2367);
+    let abcd_37 = _mm256_unpackhi_epi64(ab_2367, cd_2367);
+    let efgh_04 = _mm256_unpacklo_epi64(ef_0145, gh_0145);
+    let efgh_15 = _mm256_unpackhi_epi64(ef_0145, gh_0145);
+    let efgh_26 = _mm256_unpacklo_epi64(ef_2367, gh_2367);
+    let efgh_37 = _mm256_unpackhi_epi64(ef_2367, gh_2367);
+
+    vecs[0] = _mm256_permute2x128_si256(abcd_04, efgh_04, 0x20);
+    vecs[1] = _mm256_permute2x128_si256(abcd_15, efgh_15, 0x20);
+    vecs[2] = _mm256_permute2x128_si256(abcd_26, efgh_26, 0x20);
+    vecs[3] = _mm256_permute2x128_si256(abcd_37, efgh_37, 0x20);
+    vecs[4] = _mm256_permute2x128_si256(abcd_04, efgh_04, 0x31);
+    vecs[5] = _mm256_permute2x128_si256(abcd_15, efgh_15, 0x31);
+    vecs[6] = _mm256_permute2x128_si256(abcd_26, efgh_26, 0x31);
+    vecs[7] = _mm256_permute2x128_si256(abcd_37, efgh_37, 0x31);
+}
+
+// Load the block at `block_offset` from each input, transposed so that
+// msg[word] holds the given word of every input's block.
+#[inline(always)]
+unsafe fn transpose_msg_vecs<const N: usize>(
+    inputs: &[&[u8; N]; DEGREE],
+    block_offset: usize,
+) -> [__m256i; 16] {
+    let mut vecs = [_mm256_setzero_si256(); 16];
+    for (half, half_vecs) in vecs.chunks_exact_mut(DEGREE).enumerate() {
+        for (input, vec) in inputs.iter().zip(half_vecs.iter_mut()) {
+            *vec = loadu(array_ref!(input, block_offset + 32 * half, 32).as_ptr());
+        }
+        transpose_vecs(array_ref_mut!(half_vecs, 0, DEGREE));
+    }
+    vecs
+}
+
+#[inline(always)]
+unsafe fn counter_vecs(counter: u64, increment_counter: IncrementCounter) -> (__m256i, __m256i) {
+    // When incrementing, each lane gets the counter plus its lane number.
+    let mask = if increment_counter.yes() { !0 } else { 0 };
+    (
+        set8(
+            counter_low(counter),
+            counter_low(counter + (mask & 1)),
+            counter_low(counter + (mask & 2)),
+            counter_low(counter + (mask & 3)),
+            counter_low(counter + (mask & 4)),
+            counter_low(counter + (mask & 5)),
+            counter_low(counter + (mask & 6)),
+            counter_low(counter + (mask & 7)),
+        ),
+        set8(
+            counter_high(counter),
+            counter_high(counter + (mask & 1)),
+            counter_high(counter + (mask & 2)),
+            counter_high(counter + (mask & 3)),
+            counter_high(counter + (mask & 4)),
+            counter_high(counter + (mask & 5)),
+            counter_high(counter + (mask & 6)),
+            counter_high(counter + (mask & 7)),
+        ),
+    )
+}
+
+#[target_feature(enable = "avx2")]
+#[allow(clippy::too_many_arguments)]
+unsafe fn hash8<const N: usize>(
+    inputs: &[&[u8; N]; DEGREE],
+    key: &CVWords,
+    counter: u64,
+    increment_counter: IncrementCounter,
+    flags: u8,
+    flags_start: u8,
+    flags_end: u8,
+    out: &mut [u8; DEGREE * OUT_LEN],
+) {
+    debug_assert_eq!(N % BLOCK_LEN, 0, "uneven blocks");
+    let mut h_vecs = [
+        set1(key[0]),
+        set1(key[1]),
+        set1(key[2]),
+        set1(key[3]),
+        set1(key[4]),
+        set1(key[5]),
+        set1(key[6]),
+        set1(key[7]),
+    ];
+    let (counter_low_vec, counter_high_vec) = counter_vecs(counter, increment_counter);
+    let mut block_flags = flags | flags_start;
+
+    for block_offset in (0..N).step_by(BLOCK_LEN) {
+        if block_offset + BLOCK_LEN == N {
+            block_flags |= flags_end;
+        }
+        let msg_vecs = transpose_msg_vecs(inputs, block_offset);
+        let mut v = [
+            h_vecs[0],
+            h_vecs[1],
+            h_vecs[2],
+            h_vecs[3],
+            h_vecs[4],
+            h_vecs[5],
+            h_vecs[6],
+            h_vecs[7],
+            set1(IV[0]),
+            set1(IV[1]),
+            set1(IV[2]),
+            set1(IV[3]),
+            counter_low_vec,
+            counter_high_vec,
+            set1(BLOCK_LEN as u32),
+            set1(block_flags as u32),
+        ];
+
+        round(&mut v, &msg_vecs, 0);
+        round(&mut v, &msg_vecs, 1);
+        round(&mut v, &msg_vecs, 2);
+        round(&mut v, &msg_vecs, 3);
+        round(&mut v, &msg_vecs, 4);
+        round(&mut v, &msg_vecs, 5);
+        round(&mut v, &msg_vecs, 6);
+
+        for i in 0..8 {
+            h_vecs[i] = xor(v[i], v[i + 8]);
+        }
+        block_flags = flags;
+    }
+
+    // Transpose the CVs back into one contiguous output per input.
+    transpose_vecs(&mut h_vecs);
+    for (h_vec, output) in h_vecs.iter().zip(out.chunks_exact_mut(OUT_LEN)) {
+        storeu(*h_vec, output.as_mut_ptr());
+    }
+}
+
+#[target_feature(enable = "avx2")]
+#[allow(clippy::too_many_arguments)]
+pub unsafe fn hash_many<const N: usize>(
+    inputs: &[&[u8; N]],
+    key: &CVWords,
+    mut counter: u64,
+    increment_counter: IncrementCounter,
+    flags: u8,
+    flags_start: u8,
+    flags_end: u8,
+    mut out: &mut [u8],
+) {
+    debug_assert!(out.len() >= inputs.len() * OUT_LEN, "out too short");
+    let mut groups = inputs.chunks_exact(DEGREE);
+    for group in &mut groups {
+        hash8(
+            array_ref!(group, 0, DEGREE),
+            key,
+            counter,
+            increment_counter,
+            flags,
+            flags_start,
+            flags_end,
+            array_ref_mut!(out, 0, DEGREE * OUT_LEN),
+        );
+        if increment_counter.yes() {
+            counter += DEGREE as u64;
+        }
+        out = &mut out[DEGREE * OUT_LEN..];
+    }
+
+    crate::sse41::hash_many(
+        groups.remainder(),
+        key,
+        counter,
+        increment_counter,
+        flags,
+        flags_start,
+        flags_end,
+        out,
+    );
+}
@@ -0,0 +1,721 @@
+//! The [Bao](https://github.com/oconnor663/bao) tree encoding, for verified
+//! streaming of BLAKE3 hashed content.
+//!
+//! The combined encoding interleaves the input with the parent nodes of its
+//! BLAKE3 tree, so that a reader holding only the root [`Hash`] can verify
+//! each chunk as it arrives, rather than only at the end. It starts with the
+//! content length as an 8-byte little-endian header, followed by the tree in
+//! pre-order: each parent node is the 32-byte chaining values of its left and
+//! right children, and comes right before the encoding of those children. The
+//! leaves are the 1 KiB chunks of the input, with a shorter final chunk. The
+//! tree has exactly the shape that BLAKE3 gives it, so the root hash of an
+//! encoding is the regular [`hash`](../fn.hash.html) of its content, and the
+//! bytes are identical to those produced by the `bao` crate.
+//!
+//! The outboard encoding is the same header and parent nodes without the
+//! content, for content that's stored separately and left untouched. It's
+//! about 1/16 the size of the content.
+//!
+//! The `_grouped` variants of the functions and constructors take a
+//! `chunk_group_log`, which makes each leaf a group of `2^chunk_group_log`
+//! chunks instead of a single chunk, like newer formats derived from Bao.
+//! Everything above the leaves is the same BLAKE3 tree, so the root hash
+//! doesn't change, but each increment of `chunk_group_log` halves the number
+//! of parent nodes. With a `chunk_group_log` of 4, for 16 KiB leaves, the
+//! outboard encoding is about 0.4% of the content. The other side has to use
+//! the same `chunk_group_log`, since it isn't recorded in the encoding. The
+//! default of 0 is the format of the `bao` crate.
+//!
+//! This module requires the `bao` Cargo feature, which is disabled by default.
+//!
+//! # Example
+//!
+//! ```
+//! use blake3_balanced::bao;
+//!
+//! let input = vec![0xab; 10_000];
+//! let (encoded, hash) = bao::encode::encode(&input);
+//! assert_eq!(hash, blake3_balanced::hash(&input));
+//! assert_eq!(encoded.len() as u128, bao::encode::encoded_size(input.len() as u64));
+//!
+//! let decoded = bao::decode::decode(&encoded, &hash)?;
+//! assert_eq!(input, decoded);
+//! # Ok::<(), bao::decode::Error>(())
+//! ```
+//!
+//! [`Hash`]: ../struct.Hash.html
+
+pub mod decode;
+pub mod encode;
+
+use crate::{join::SerialJoin, ChunkState, Output, BLOCK_LEN, CHUNK_LEN, IV, PARENT};
+
+/// The size of the content length header at the start of an encoding.
+pub const HEADER_SIZE: usize = 8;
+
+/// The size of a parent node, holding the chaining values of its two children.
+pub const PARENT_SIZE: usize = 2 * crate::OUT_LEN;
+
+/// The largest supported `chunk_group_log`, for leaves of 64 MiB. Decoders
+/// buffer a whole leaf at a time, so much smaller values are usual.
+pub const MAX_CHUNK_GROUP_LOG: u8 = 16;
+
+// The length of a full leaf, for a given chunk_group_log.
+fn group_len(chunk_group_log: u8) -> u64 {
+    assert!(
+        chunk_group_log <= MAX_CHUNK_GROUP_LOG,
+        "chunk_group_log greater than MAX_CHUNK_GROUP_LOG"
+    );
+    (CHUNK_LEN as u64) << chunk_group_log
+}
+
+// The Output of a leaf, which is a whole subtree of up to 2^chunk_group_log
+// chunks. The caller decides whether that becomes a chaining value or the
+// root hash.
+fn leaf_output(leaf: &[u8], chunk_counter: u64) -> Output {
+    if leaf.len() <= CHUNK_LEN {
+        return ChunkState::new(IV, chunk_counter, 0).update(leaf).output();
+    }
+    Output {
+        input_chaining_value: *IV,
+        block: crate::compress_subtree_to_parent_node::<SerialJoin>(leaf, IV, chunk_counter, 0),
+        block_len: BLOCK_LEN as u8,
+        counter: 0,
+        flags: PARENT,
+    }
+}
+
+// Whether the subtree of content at `start` of length `len` is part of the
+// slice at `slice_start` of length `slice_len`. Every slice includes at least
+// one leaf, so that it verifies the length: an empty slice is treated as one
+// byte long, and a slice that starts past the end includes the final leaf.
+fn in_slice(
+    start: u64,
+    len: u64,
+    content_len: u64,
+    group_len: u64,
+    slice_start: u64,
+    slice_len: u64,
+) -> bool {
+    let final_leaf_start = (encode::count_leaves(content_len, group_len) - 1) * group_len;
+    let range_start = core::cmp::min(slice_start, final_leaf_start);
+    let range_end = slice_start.saturating_add(core::cmp::max(slice_len, 1));
+    let range_end = core::cmp::max(range_end, range_start + 1);
+    // The empty root leaf still counts as covering its start.
+    start < range_end && range_start < start + core::cmp::max(len, 1)
+}
+
+// As crate::left_len(), but for the u64 lengths of encodings, which might not
+// fit in a usize. The header of an encoding being decoded is untrusted, so
+// this has to work all the way up to u64::MAX.
+fn left_len(content_len: u64) -> u64 {
+    debug_assert!(content_len > CHUNK_LEN as u64);
+    let full_chunks = (content_len - 1) / CHUNK_LEN as u64;
+    // The largest power of two less than or equal to full_chunks.
+    (1 << (63 - full_chunks.leading_zeros())) * CHUNK_LEN as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use blake3::hazmat::HasherExt;
+    use core::cmp;
+    use std::io::{Cursor, Read, Seek, SeekFrom};
+
+    // Lengths around the chunk boundaries where the tree changes shape.
+    pub(crate) const TEST_LENS: &[usize] = &[
+        0,
+        1,
+        CHUNK_LEN - 1,
+        CHUNK_LEN,
+        CHUNK_LEN + 1,
+        2 * CHUNK_LEN,
+        2 * CHUNK_LEN + 1,
+        3 * CHUNK_LEN,
+        4 * CHUNK_LEN,
+        4 * CHUNK_LEN + 1,
+        7 * CHUNK_LEN - 1,
+        8 * CHUNK_LEN + 1,
+        16 * CHUNK_LEN,
+        31 * CHUNK_LEN + 517,
+        100 * CHUNK_LEN + 10,
+    ];
+
+    pub(crate) fn test_input(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i % 251) as u8).collect()
+    }
+
+    // The chaining value of a non-root subtree, computed with the blake3
+    // crate rather than anything in this crate.
+    fn subtree_cv(input: &[u8], offset: usize) -> [u8; 32] {
+        blake3::Hasher::new()
+            .set_input_offset(offset as u64)
+            .update(input)
+            .finalize_non_root()
+    }
+
+    // Walk a pre-order encoding of `input` at `offset`, checking every parent
+    // node and chunk, and return the remaining encoded bytes. The outboard
+    // encoding has no chunks to check.
+    fn check_subtree<'a>(
+        input: &[u8],
+        offset: usize,
+        group_len: usize,
+        outboard: bool,
+        encoded: &'a [u8],
+    ) -> &'a [u8] {
+        if input.len() <= group_len {
+            if outboard {
+                return encoded;
+            }
+            assert_eq!(input, &encoded[..input.len()], "chunk at {}", offset);
+            return &encoded[input.len()..];
+        }
+        let (left, right) = input.split_at(crate::left_len(input.len()));
+        let right_offset = offset + left.len();
+        assert_eq!(
+            subtree_cv(left, offset),
+            encoded[..32],
+            "left CV at {}",
+            offset
+        );
+        assert_eq!(
+            subtree_cv(right, right_offset),
+            encoded[32..64],
+            "right CV at {}",
+            offset
+        );
+        let rest = check_subtree(left, offset, group_len, outboard, &encoded[PARENT_SIZE..]);
+        check_subtree(right, right_offset, group_len, outboard, rest)
+    }
+
+    pub(crate) fn check_encoding(input: &[u8], chunk_group_log: u8, encoded: &[u8]) {
+        assert_eq!(
+            encoded.len() as u128,
+            encode::encoded_size_grouped(input.len() as u64, chunk_group_log)
+        );
+        assert_eq!(&(input.len() as u64).to_le_bytes(), &encoded[..HEADER_SIZE]);
+        let group_len = CHUNK_LEN << chunk_group_log;
+        let rest = check_subtree(input, 0, group_len, false, &encoded[HEADER_SIZE..]);
+        assert!(rest.is_empty(), "trailing bytes");
+    }
+
+    pub(crate) fn check_outboard(input: &[u8], chunk_group_log: u8, outboard: &[u8]) {
+        assert_eq!(
+            outboard.len() as u128,
+            encode::outboard_size_grouped(input.len() as u64, chunk_group_log)
+        );
+        assert_eq!(
+            &(input.len() as u64).to_le_bytes(),
+            &outboard[..HEADER_SIZE]
+        );
+        let group_len = CHUNK_LEN << chunk_group_log;
+        let rest = check_subtree(input, 0, group_len, true, &outboard[HEADER_SIZE..]);
+        assert!(rest.is_empty(), "trailing bytes");
+    }
+
+    #[test]
+    fn encode() {
+        for &len in TEST_LENS {
+            let input = test_input(len);
+            let (encoded, hash) = encode::encode(&input);
+            assert_eq!(hash, *blake3::hash(&input).as_bytes(), "len {}", len);
+            check_encoding(&input, 0, &encoded);
+
+            let (encoded_from_reader, hash_from_reader) =
+                encode::encode_from_reader(&input[..]).unwrap();
+            assert_eq!(hash, hash_from_reader);
+            assert_eq!(encoded, encoded_from_reader);
+        }
+    }
+
+    #[test]
+    fn outboard() {
+        for &len in TEST_LENS {
+            let input = test_input(len);
+            let (outboard, hash) = encode::outboard(&input);
+            assert_eq!(hash, *blake3::hash(&input).as_bytes(), "len {}", len);
+            check_outboard(&input, 0, &outboard);
+
+            // Split the input, so that the reader returns a short read.
+            let (first, second) = input.split_at(len / 3);
+            let (outboard_from_reader, hash_from_reader) =
+                encode::outboard_from_reader(first.chain(second)).unwrap();
+            assert_eq!(hash, hash_from_reader, "len {}", len);
+            assert_eq!(outboard, outboard_from_reader, "len {}", len);
+        }
+    }
+
+    #[test]
+    fn encoded_size() {
+        assert_eq!(encode::encoded_size(0), HEADER_SIZE as u128);
+        assert_eq!(encode::encoded_size(1), HEADER_SIZE as u128 + 1);
+        assert_eq!(
+            encode::encoded_size(CHUNK_LEN as u64 + 1),
+            (HEADER_SIZE + PARENT_SIZE + CHUNK_LEN + 1) as u128
+        );
+        // The largest possible input has 2^54 chunks and 2^54 - 1 parents.
+        assert_eq!(
+            encode::encoded_size(u64::MAX),
+            HEADER_SIZE as u128 + u64::MAX as u128 + ((1u128 << 54) - 1) * PARENT_SIZE as u128
+        );
+    }
+
+    #[test]
+    fn left_len_u64() {
+        for &len in TEST_LENS {
+            if len > CHUNK_LEN {
+                assert_eq!(left_len(len as u64), crate::left_len(len) as u64);
+            }
+        }
+        assert_eq!(left_len(u64::MAX), 1 << 63);
+    }
+
+    // Read everything a decoder will give, a few bytes at a time, and return
+    // the bytes along with the error that stopped it, if any.
+    fn read_all(mut decoder: impl Read) -> (Vec<u8>, Option<decode::Error>) {
+        let mut output = Vec::new();
+        let mut buf = [0; 7];
+        loop {
+            match decoder.read(&mut buf) {
+                Ok(0) => return (output, None),
+                Ok(n) => output.extend_from_slice(&buf[..n]),
+                Err(err) => return (output, Some(err.into())),
+            }
+        }
+    }
+
+    #[test]
+    fn decode() {
+        for &len in TEST_LENS {
+            let input = test_input(len);
+            let (encoded, hash) = encode::encode(&input);
+            assert_eq!(
+                input,
+                decode::decode(&encoded, &hash).unwrap(),
+                "len {}",
+                len
+            );
+            let (output, err) = read_all(decode::Decoder::new(&encoded[..], &hash));
+            assert!(err.is_none(), "len {}", len);
+            assert_eq!(input, output, "len {}", len);
+        }
+    }
+
+    #[test]
+    fn decode_corrupt() {
+        for &len in TEST_LENS {
+            let input = test_input(len);
+            let (encoded, hash) = encode::encode(&input);
+            for i in (0..encoded.len()).step_by(61) {
+                let mut corrupt = encoded.clone();
+                corrupt[i] ^= 1;
+                let (output, err) = read_all(decode::Decoder::new(&corrupt[..], &hash));
+                // Nothing beneath the corruption is ever returned.
+                assert_eq!(
+                    &input[..output.len()],
+                    &output[..],
+                    "len {} byte {}",
+                    len,
+                    i
+                );
+                match err {
+                    Some(decode::Error::HashMismatch) => {}
+                    // A bad header can run off the end of the encoding.
+                    Some(decode::Error::Truncated) if i < HEADER_SIZE => {}
+                    other => panic!("len {} byte {}: {:?}", len, i, other),
+                }
+            }
+
+            let mut wrong_hash = *hash.as_bytes();
+            wrong_hash[0] ^= 1;
+            let err = decode::decode(&encoded, &wrong_hash.into()).unwrap_err();
+            assert!(matches!(err, decode::Error::HashMismatch), "len {}", len);
+
+            let err = decode::decode(&encoded[..encoded.len() - 1], &hash).unwrap_err();
+            assert!(matches!(err, decode::Error::Truncated), "len {}", len);
+        }
+    }
+
+    #[test]
+    fn decode_seek() {
+        for &len in TEST_LENS {
+            let input = test_input(len);
+            let (encoded, hash) = encode::encode(&input);
+            let mut decoder = decode::Decoder::new(Cursor::new(&encoded), &hash);
+            let targets = [
+                0,
+                1,
+                len / 2,
+                len.saturating_sub(1),
+                len,
+                len + 1,
+                3 * CHUNK_LEN,
+            ];
+            // Seek forwards and backwards, including within the buffered chunk.
+            for &target in targets.iter().chain(targets.iter().rev()) {
+                let position = decoder.seek(SeekFrom::Start(target as u64)).unwrap();
+                assert_eq!(position, target as u64);
+                let mut output = [0; 3];
+                let n = decoder.read(&mut output).unwrap();
+                let expected = &input[cmp::min(target, len)..];
+                assert_eq!(&expected[..cmp::min(3, expected.len())], &output[..n]);
+            }
+
+            decoder.seek(SeekFrom::Start(len as u64 / 3)).unwrap();
+            let (output, err) = read_all(&mut decoder);
+            assert!(err.is_none());
+            assert_eq!(&input[len / 3..], &output[..]);
+            assert_eq!(decoder.stream_position().unwrap(), len as u64);
+            assert_eq!(decoder.seek(SeekFrom::End(-(len as i64))).unwrap(), 0);
+            assert!(decoder.seek(SeekFrom::Current(-1)).is_err());
+
+            // Seeking to the end verifies the final chunk, and so the length.
+            if len == 0 {
+                continue;
+            }
+            let mut corrupt = encoded.clone();
+            *corrupt.last_mut().unwrap() ^= 1;
+            let mut decoder = decode::Decoder::new(Cursor::new(&corrupt), &hash);
+            let err: decode::Error = decoder.seek(SeekFrom::End(0)).unwrap_err().into();
+            assert!(matches!(err, decode::Error::HashMismatch), "len {}", len);
+        }
+    }
+
+    #[test]
+    fn decode_outboard() {
+        for &len in TEST_LENS {
+            let input = test_input(len);
+            let (outboard, hash) = encode::outboard(&input);
+            let decoder = decode::Decoder::new_outboard(&input[..], &outboard[..], &hash);
+            let (output, err) = read_all(decoder);
+            assert!(err.is_none(), "len {}", len);
+            assert_eq!(input, output, "len {}", len);
+
+            for i in (0..outboard.len()).step_by(23) {
+                let mut corrupt = outboard.clone();
+                corrupt[i] ^= 1;
+                let decoder = decode::Decoder::new_outboard(&input[..], &corrupt[..], &hash);
+                let (output, err) = read_all(decoder);
+                assert_eq!(
+                    &input[..output.len()],
+                    &output[..],
+                    "len {} byte {}",
+                    len,
+                    i
+                );
+                assert!(err.is_some(), "len {} byte {}", len, i);
+            }
+            for i in (0..len).step_by(401) {
+                let mut corrupt = input.clone();
+                corrupt[i] ^= 1;
+                let decoder = decode::Decoder::new_outboard(&corrupt[..], &outboard[..], &hash);
+                let (output, err) = read_all(decoder);
+                assert_eq!(
+                    &input[..output.len()],
+                    &output[..],
+                    "len {} byte {}",
+                    len,
+                    i
+                );
+                assert!(output.len() <= i, "len {} byte {}", len, i);
+                assert!(
+                    matches!(err, Some(decode::Error::HashMismatch)),
+                    "len {}",
+                    len
+                );
+            }
+
+            let mut decoder =
+                decode::Decoder::new_outboard(Cursor::new(&input), Cursor::new(&outboard), &hash);
+            for &target in &[len / 2, 0, len, len.saturating_sub(CHUNK_LEN + 1)] {
+                decoder.seek(SeekFrom::Start(target as u64)).unwrap();
+                let (output, err) = read_all(&mut decoder);
+                assert!(err.is_none(), "len {} target {}", len, target);
+                assert_eq!(
+                    &input[target..],
+                    &output[..],
+                    "len {} target {}",
+                    len,
+                    target
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn slices() {
+        for &len in TEST_LENS {
+            let input = test_input(len);
+            let (encoded, hash) = encode::encode(&input);
+            let (outboard, _) = encode::outboard(&input);
+            let ranges = [
+                (0, len),
+                (0, 0),
+                (1, 1),
+                (len / 2, 2 * CHUNK_LEN + 3),
+                (CHUNK_LEN - 1, 2),
+                (len.saturating_sub(1), 10),
+                (len, 0),
+                (len + 5000, 1),
+            ];
+            for &(start, slice_len) in &ranges {
+                let slice =
+                    encode::extract_slice(Cursor::new(&encoded), start as u64, slice_len as u64)
+                        .unwrap();
+                let slice_from_outboard = encode::extract_slice_outboard(
+                    Cursor::new(&input),
+                    Cursor::new(&outboard),
+                    start as u64,
+                    slice_len as u64,
+                )
+                .unwrap();
+                assert_eq!(
+                    slice, slice_from_outboard,
+                    "len {} range {}+{}",
+                    len, start, slice_len
+                );
+                // A slice holds the chunks it touches and the parents on
+                // their paths, which for the whole content is everything.
+                if start == 0 && slice_len >= len {
+                    assert_eq!(slice, encoded);
+                }
+                let chunks = cmp::max(slice_len, 1) / CHUNK_LEN + 2;
+                let depth = 64
+                    - encode::count_leaves(len as u64, CHUNK_LEN as u64).leading_zeros() as usize;
+                assert!(slice.len() <= HEADER_SIZE + chunks * (CHUNK_LEN + depth * PARENT_SIZE));
+
+                let decoder =
+                    decode::SliceDecoder::new(&slice[..], &hash, start as u64, slice_len as u64);
+                let (output, err) = read_all(decoder);
+                assert!(err.is_none(), "len {} range {}+{}", len, start, slice_len);
+                let expected_start = cmp::min(start, len);
+                let expected_end = cmp::min(start + slice_len, len);
+                assert_eq!(&input[expected_start..expected_end], &output[..]);
+
+                for i in (0..slice.len()).step_by(97) {
+                    let mut corrupt = slice.clone();
+                    corrupt[i] ^= 1;
+                    let decoder = decode::SliceDecoder::new(
+                        &corrupt[..],
+                        &hash,
+                        start as u64,
+                        slice_len as u64,
+                    );
+                    let (output, err) = read_all(decoder);
+                    let expected = &input[expected_start..expected_end];
+                    assert_eq!(&expected[..output.len()], &output[..]);
+                    // A slice that doesn't reach the end only verifies the
+                    // length as far as the tree shape along its path, so a
+                    // bad header can go unnoticed while the content is right.
+                    assert!(
+                        err.is_some() || (i < HEADER_SIZE && output == expected),
+                        "len {} range {}+{} byte {}",
+                        len,
+                        start,
+                        slice_len,
+                        i
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn chunk_groups() {
+        for &chunk_group_log in &[1, 2, 4] {
+            let group_len = CHUNK_LEN << chunk_group_log;
+            for &len in TEST_LENS {
+                let input = test_input(len);
+                let expected_hash = *blake3::hash(&input).as_bytes();
+                let (encoded, hash) = encode::encode_grouped(&input, chunk_group_log);
+                assert_eq!(hash, expected_hash, "len {} log {}", len, chunk_group_log);
+                check_encoding(&input, chunk_group_log, &encoded);
+                let (outboard, hash) = encode::outboard_grouped(&input, chunk_group_log);
+                assert_eq!(hash, expected_hash);
+                check_outboard(&input, chunk_group_log, &outboard);
+                let (outboard_from_reader, hash) =
+                    encode::outboard_from_reader_grouped(&input[..], chunk_group_log).unwrap();
+                assert_eq!(hash, expected_hash);
+                assert_eq!(outboard, outboard_from_reader);
+
+                let decoded = decode::decode_grouped(&encoded, &hash, chunk_group_log).unwrap();
+                assert_eq!(input, decoded);
+                let mut decoder = decode::Decoder::new_outboard_grouped(
+                    Cursor::new(&input),
+                    Cursor::new(&outboard),
+                    &hash,
+                    chunk_group_log,
+                );
+                let (output, err) = read_all(&mut decoder);
+                assert!(err.is_none());
+                assert_eq!(input, output);
+                decoder.seek(SeekFrom::Start(len as u64 / 3)).unwrap();
+                let (output, err) = read_all(&mut decoder);
+                assert!(err.is_none());
+                assert_eq!(&input[len / 3..], &output[..]);
+
+                // The wrong chunk_group_log doesn't verify, unless it gives
+                // the same tree.
+                if len > CHUNK_LEN {
+                    let err = decode::decode(&encoded, &hash).unwrap_err();
+                    assert!(matches!(err, decode::Error::HashMismatch));
+                }
+
+                for i in (0..encoded.len()).step_by(89) {
+                    let mut corrupt = encoded.clone();
+                    corrupt[i] ^= 1;
+                    let decoder =
+                        decode::Decoder::new_grouped(&corrupt[..], &hash, chunk_group_log);
+                    let (output, err) = read_all(decoder);
+                    assert_eq!(&input[..output.len()], &output[..]);
+                    assert!(err.is_some(), "len {} byte {}", len, i);
+                }
+
+                let (start, slice_len) = (len / 2, CHUNK_LEN + 1);
+                let slice = encode::extract_slice_grouped(
+                    Cursor::new(&encoded),
+                    start as u64,
+                    slice_len as u64,
+                    chunk_group_log,
+                )
+                .unwrap();
+                let slice_from_outboard = encode::extract_slice_outboard_grouped(
+                    Cursor::new(&input),
+                    Cursor::new(&outboard),
+                    start as u64,
+                    slice_len as u64,
+                    chunk_group_log,
+                )
+                .unwrap();
+                assert_eq!(slice, slice_from_outboard);
+                let decoder = decode::SliceDecoder::new_grouped(
+                    &slice[..],
+                    &hash,
+                    start as u64,
+                    slice_len as u64,
+                    chunk_group_log,
+                );
+                let (output, err) = read_all(decoder);
+                assert!(err.is_none());
+                assert_eq!(&input[start..cmp::min(start + slice_len, len)], &output[..]);
+                // The slice holds at most two leaves and their parents.
+                let depth = 64 - (len / group_len + 1).leading_zeros() as usize;
+                assert!(slice.len() <= HEADER_SIZE + 2 * (group_len + depth * PARENT_SIZE));
+            }
+        }
+
+        // A multi-GB asset with 16 KiB leaves has an outboard under 0.4%.
+        let len = 10 << 30;
+        assert!(encode::outboard_size_grouped(len, 4) * 256 < len as u128);
+        assert_eq!(
+            encode::outboard_size_grouped(len, 0),
+            encode::outboard_size(len)
+        );
+    }
+
+    // The input of the test vectors: a little-endian u32 counter starting at
+    // 1, cut off at `len`.
+    fn vector_input(len: usize) -> Vec<u8> {
+        (1u32..).flat_map(|i| i.to_le_bytes()).take(len).collect()
+    }
+
+    fn blake3_hex(bytes: &[u8]) -> String {
+        blake3::hash(bytes).to_hex().to_string()
+    }
+
+    fn flip_byte(bytes: &[u8], i: usize) -> Vec<u8> {
+        let mut corrupt = bytes.to_vec();
+        corrupt[i] ^= 1;
+        corrupt
+    }
+
+    // Known answers from the test vectors that the `bao` crate publishes, in
+    // tests/data/bao_test_vectors.json. They check the byte layout, not just
+    // the tree, and the corruptions are the offsets that bao detects.
+    #[test]
+    fn test_vectors() {
+        let vectors: serde_json::Value =
+            serde_json::from_str(include_str!("../tests/data/bao_test_vectors.json")).unwrap();
+        let usize_of = |value: &serde_json::Value| value.as_u64().unwrap() as usize;
+
+        for case in vectors["encode"].as_array().unwrap() {
+            let input = vector_input(usize_of(&case["input_len"]));
+            let (encoded, hash) = encode::encode(&input);
+            assert_eq!(hash.to_hex().as_str(), case["bao_hash"]);
+            assert_eq!(encoded.len(), usize_of(&case["output_len"]));
+            assert_eq!(blake3_hex(&encoded), case["encoded_blake3"]);
+            for i in case["corruptions"].as_array().unwrap().iter().map(usize_of) {
+                let corrupt = flip_byte(&encoded, i);
+                assert!(decode::decode(&corrupt, &hash).is_err(), "offset {}", i);
+            }
+        }
+
+        for case in vectors["outboard"].as_array().unwrap() {
+            let input = vector_input(usize_of(&case["input_len"]));
+            let (outboard, hash) = encode::outboard(&input);
+            assert_eq!(hash.to_hex().as_str(), case["bao_hash"]);
+            assert_eq!(outboard.len(), usize_of(&case["output_len"]));
+            assert_eq!(blake3_hex(&outboard), case["encoded_blake3"]);
+            for i in case["outboard_corruptions"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(usize_of)
+            {
+                let corrupt = flip_byte(&outboard, i);
+                let decoder = decode::Decoder::new_outboard(&input[..], &corrupt[..], &hash);
+                assert!(read_all(decoder).1.is_some(), "outboard offset {}", i);
+            }
+            for i in case["input_corruptions"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(usize_of)
+            {
+                let corrupt = flip_byte(&input, i);
+                let decoder = decode::Decoder::new_outboard(&corrupt[..], &outboard[..], &hash);
+                assert!(read_all(decoder).1.is_some(), "input offset {}", i);
+            }
+        }
+
+        for case in vectors["slice"].as_array().unwrap() {
+            let input = vector_input(usize_of(&case["input_len"]));
+            let (encoded, hash) = encode::encode(&input);
+            let (outboard, _) = encode::outboard(&input);
+            assert_eq!(hash.to_hex().as_str(), case["bao_hash"]);
+            for slice_case in case["slices"].as_array().unwrap() {
+                let start = slice_case["start"].as_u64().unwrap();
+                let len = slice_case["len"].as_u64().unwrap();
+                let slice = encode::extract_slice(Cursor::new(&encoded), start, len).unwrap();
+                assert_eq!(slice.len(), usize_of(&slice_case["output_len"]));
+                assert_eq!(blake3_hex(&slice), slice_case["output_blake3"]);
+                let slice_from_outboard = encode::extract_slice_outboard(
+                    Cursor::new(&input),
+                    Cursor::new(&outboard),
+                    start,
+                    len,
+                )
+                .unwrap();
+                assert_eq!(slice, slice_from_outboard);
+
+                let (output, err) =
+                    read_all(decode::SliceDecoder::new(&slice[..], &hash, start, len));
+                assert!(err.is_none());
+                let content_start = cmp::min(start as usize, input.len());
+                let content_end = cmp::min(content_start + len as usize, input.len());
+                assert_eq!(output, &input[content_start..content_end]);
+                for i in slice_case["corruptions"]
+                    .as_array()
+                    .unwrap()
+                    .iter()
+                    .map(usize_of)
+                {
+                    let corrupt = flip_byte(&slice, i);
+                    let decoder = decode::SliceDecoder::new(&corrupt[..], &hash, start, len);
+                    assert!(read_all(decoder).1.is_some(), "slice offset {}", i);
+                }
+            }
+        }
+    }
+}
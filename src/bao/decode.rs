@@ -0,0 +1,636 @@
+//! Verifying and decoding the combined and outboard encodings, and slices.
+
+use super::encode::{count_leaves, encoded_subtree_size};
+use super::{group_len, in_slice, leaf_output, left_len, HEADER_SIZE, PARENT_SIZE};
+use crate::platform::{array_ref, constant_time_eq};
+use crate::{parent_node_output, CVBytes, Hash, Output, CHUNK_LEN, IV, MAX_DEPTH, OUT_LEN};
+use arrayvec::ArrayVec;
+use core::convert::TryFrom;
+use std::cmp;
+use std::fmt;
+use std::io::{self, Read, Seek, SeekFrom};
+
+/// The errors that can happen while decoding.
+///
+/// The `Read` and `Seek` implementations of [`Decoder`] return these wrapped
+/// in an [`io::Error`], with the kind `InvalidData` for
+/// [`HashMismatch`](#variant.HashMismatch) and `UnexpectedEof` for
+/// [`Truncated`](#variant.Truncated). Converting that `io::Error` back into
+/// an `Error` with `From` recovers the original.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum Error {
+    /// A parent node or chunk didn't match the chaining value that the tree
+    /// above it expects, or the root node didn't match the expected hash.
+    /// Either the encoding is corrupt, or it encodes different content.
+    HashMismatch,
+    /// The encoding ended before all of its content and parent nodes did.
+    Truncated,
+    /// An error from the underlying reader.
+    Io(io::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::HashMismatch => write!(f, "hash mismatch"),
+            Error::Truncated => write!(f, "encoding truncated"),
+            Error::Io(err) => err.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<Error> for io::Error {
+    fn from(err: Error) -> Self {
+        match err {
+            Error::HashMismatch => io::Error::new(io::ErrorKind::InvalidData, err),
+            Error::Truncated => io::Error::new(io::ErrorKind::UnexpectedEof, err),
+            Error::Io(err) => err,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Self {
+        if matches!(err.get_ref(), Some(inner) if inner.is::<Error>()) {
+            // This unwrapping can't fail, because we just checked it.
+            *err.into_inner().unwrap().downcast::<Error>().unwrap()
+        } else if err.kind() == io::ErrorKind::UnexpectedEof {
+            Error::Truncated
+        } else {
+            Error::Io(err)
+        }
+    }
+}
+
+/// Decode a whole combined encoding in memory, verifying it against `hash`,
+/// and return the content.
+pub fn decode(encoded: impl AsRef<[u8]>, hash: &Hash) -> Result<Vec<u8>, Error> {
+    decode_grouped(encoded, hash, 0)
+}
+
+/// As [`decode`], for an encoding with leaves of `2^chunk_group_log` chunks.
+///
+/// # Panics
+///
+/// Panics if `chunk_group_log` is greater than
+/// [`MAX_CHUNK_GROUP_LOG`](../constant.MAX_CHUNK_GROUP_LOG.html).
+pub fn decode_grouped(
+    encoded: impl AsRef<[u8]>,
+    hash: &Hash,
+    chunk_group_log: u8,
+) -> Result<Vec<u8>, Error> {
+    let mut content = Vec::new();
+    Decoder::new_grouped(encoded.as_ref(), hash, chunk_group_log).read_to_end(&mut content)?;
+    Ok(content)
+}
+
+// A subtree that hasn't been read yet, along with where to find it and what
+// it has to hash to.
+#[derive(Clone, Copy)]
+struct Subtree {
+    start: u64,
+    len: u64,
+    // The position of this subtree's first node in the encoding. This only
+    // matters for seeking. It's a u128, because the header is untrusted, and
+    // a huge content length would overflow a u64.
+    encoded_offset: u128,
+    // The chaining value this subtree should have, or the root hash.
+    expected: CVBytes,
+    is_root: bool,
+}
+
+impl Subtree {
+    fn is_leaf(&self, group_len: u64) -> bool {
+        self.len <= group_len
+    }
+
+    // Verify the Output of this subtree's top node. The root node has to be
+    // finalized with the ROOT flag, and every other node gives a chaining
+    // value.
+    fn verify(&self, output: &Output) -> Result<(), Error> {
+        let found = if self.is_root {
+            output.root_hash().0
+        } else {
+            output.chaining_value()
+        };
+        if constant_time_eq(&found, &self.expected) {
+            Ok(())
+        } else {
+            Err(Error::HashMismatch)
+        }
+    }
+
+    fn children(&self, parent: &[u8; PARENT_SIZE], group_len: u64) -> (Subtree, Subtree) {
+        let left_len = left_len(self.len);
+        let left = Subtree {
+            start: self.start,
+            len: left_len,
+            encoded_offset: self.encoded_offset + PARENT_SIZE as u128,
+            expected: *array_ref!(parent, 0, OUT_LEN),
+            is_root: false,
+        };
+        let right = Subtree {
+            start: self.start + left_len,
+            len: self.len - left_len,
+            encoded_offset: left.encoded_offset + encoded_subtree_size(left_len, group_len),
+            expected: *array_ref!(parent, OUT_LEN, OUT_LEN),
+            is_root: false,
+        };
+        (left, right)
+    }
+}
+
+/// A reader that decodes the combined encoding, or content along with its
+/// outboard encoding, verifying it against the root hash as it goes.
+///
+/// Every parent node and chunk is checked before any of the content beneath
+/// it is returned, so the bytes that `read` returns are always authentic.
+/// Reading stops with an error at the first chunk or parent node that
+/// doesn't verify, and the length header is verified along with the chunks.
+/// After an error, the decoder can only be used again by seeking.
+///
+/// The leaves of the tree are single chunks, unless the decoder is created
+/// with a `chunk_group_log` by one of the `_grouped` constructors, which
+/// have to match the encoding. The decoder buffers one leaf at a time.
+///
+/// When the underlying readers also implement `Seek`, so does the decoder.
+/// Seeking is in terms of content bytes, and verifies the parent nodes on
+/// the way down to the target chunk. Seeking to or past the end verifies the
+/// final chunk, so that the length is authenticated.
+///
+/// # Example
+///
+/// ```
+/// use blake3_balanced::bao;
+/// use std::io::prelude::*;
+///
+/// let input = vec![0xab; 10_000];
+/// let (encoded, hash) = bao::encode::encode(&input);
+///
+/// let mut decoder = bao::decode::Decoder::new(&encoded[..], &hash);
+/// let mut output = Vec::new();
+/// decoder.read_to_end(&mut output)?;
+/// assert_eq!(input, output);
+///
+/// // A corrupt encoding fails, without returning the corrupt chunk.
+/// let mut corrupt = encoded.clone();
+/// corrupt[9_000] ^= 1;
+/// let mut decoder = bao::decode::Decoder::new(&corrupt[..], &hash);
+/// let err = decoder.read_to_end(&mut Vec::new()).unwrap_err();
+/// assert!(matches!(err.into(), bao::decode::Error::HashMismatch));
+///
+/// // The outboard encoding is read along with the unmodified content.
+/// let (outboard, hash) = bao::encode::outboard(&input);
+/// let mut decoder = bao::decode::Decoder::new_outboard(&input[..], &outboard[..], &hash);
+/// let mut output = Vec::new();
+/// decoder.read_to_end(&mut output)?;
+/// assert_eq!(input, output);
+/// # Ok::<(), std::io::Error>(())
+/// ```
+pub struct Decoder<R, O = R> {
+    inner: R,
+    // The reader of the header and parent nodes, in outboard mode. Otherwise
+    // they're in the inner reader along with the content.
+    outboard: Option<O>,
+    // The start and length of the slice, when decoding one. Subtrees outside
+    // of it aren't in the encoding.
+    slice: Option<(u64, u64)>,
+    hash: Hash,
+    group_len: u64,
+    content_len: Option<u64>,
+    // Subtrees still to be read, with the next one on top.
+    stack: ArrayVec<Subtree, { MAX_DEPTH + 1 }>,
+    // The verified leaf that reads are currently returning bytes from.
+    buf: Vec<u8>,
+    buf_start: usize,
+    buf_end: usize,
+    // The content position of buf[buf_start], or of the next leaf when the
+    // buffer is empty.
+    position: u64,
+}
+
+impl<R: Read> Decoder<R> {
+    /// Create a decoder for the given combined encoding, which has to hash to
+    /// `hash`.
+    pub fn new(inner: R, hash: &Hash) -> Self {
+        Self::new_grouped(inner, hash, 0)
+    }
+
+    /// As [`new`](#method.new), for an encoding with leaves of
+    /// `2^chunk_group_log` chunks.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `chunk_group_log` is greater than
+    /// [`MAX_CHUNK_GROUP_LOG`](../constant.MAX_CHUNK_GROUP_LOG.html).
+    pub fn new_grouped(inner: R, hash: &Hash, chunk_group_log: u8) -> Self {
+        Self::new_inner(inner, None, hash, chunk_group_log)
+    }
+}
+
+impl<R: Read, O: Read> Decoder<R, O> {
+    /// Create a decoder for the given content and its outboard encoding,
+    /// which have to hash to `hash`. The decoder reads the length header and
+    /// the parent nodes from `outboard`, and the chunks from `content`.
+    pub fn new_outboard(content: R, outboard: O, hash: &Hash) -> Self {
+        Self::new_outboard_grouped(content, outboard, hash, 0)
+    }
+
+    /// As [`new_outboard`](#method.new_outboard), for an outboard encoding
+    /// with leaves of `2^chunk_group_log` chunks.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `chunk_group_log` is greater than
+    /// [`MAX_CHUNK_GROUP_LOG`](../constant.MAX_CHUNK_GROUP_LOG.html).
+    pub fn new_outboard_grouped(content: R, outboard: O, hash: &Hash, chunk_group_log: u8) -> Self {
+        Self::new_inner(content, Some(outboard), hash, chunk_group_log)
+    }
+
+    fn new_inner(inner: R, outboard: Option<O>, hash: &Hash, chunk_group_log: u8) -> Self {
+        Self {
+            inner,
+            outboard,
+            slice: None,
+            hash: *hash,
+            group_len: group_len(chunk_group_log),
+            content_len: None,
+            stack: ArrayVec::new(),
+            buf: Vec::new(),
+            buf_start: 0,
+            buf_end: 0,
+            position: 0,
+        }
+    }
+
+    /// Return the underlying reader of the encoding, or of the content in
+    /// outboard mode.
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+
+    // Read the header or a parent node.
+    fn read_tree(&mut self, buf: &mut [u8]) -> Result<(), Error> {
+        match &mut self.outboard {
+            Some(outboard) => outboard.read_exact(buf).map_err(Error::from),
+            None => self.read_content(buf),
+        }
+    }
+
+    fn read_content(&mut self, buf: &mut [u8]) -> Result<(), Error> {
+        self.inner.read_exact(buf).map_err(Error::from)
+    }
+
+    fn root(&self, content_len: u64) -> Subtree {
+        Subtree {
+            start: 0,
+            len: content_len,
+            encoded_offset: HEADER_SIZE as u128,
+            expected: *self.hash.as_bytes(),
+            is_root: true,
+        }
+    }
+
+    // Read the length header, and start the stack with the root.
+    fn read_header(&mut self) -> Result<u64, Error> {
+        let mut header = [0; HEADER_SIZE];
+        self.read_tree(&mut header)?;
+        let content_len = u64::from_le_bytes(header);
+        self.content_len = Some(content_len);
+        self.stack.clear();
+        self.stack.push(self.root(content_len));
+        Ok(content_len)
+    }
+
+    // Read and verify the parent node at the top of a subtree, and return
+    // its children.
+    fn read_parent(&mut self, subtree: &Subtree) -> Result<(Subtree, Subtree), Error> {
+        debug_assert!(!subtree.is_leaf(self.group_len));
+        let mut parent = [0; PARENT_SIZE];
+        self.read_tree(&mut parent)?;
+        let output = parent_node_output(
+            array_ref!(parent, 0, OUT_LEN),
+            array_ref!(parent, OUT_LEN, OUT_LEN),
+            IV,
+            0,
+        );
+        subtree.verify(&output)?;
+        Ok(subtree.children(&parent, self.group_len))
+    }
+
+    // Read and verify a leaf into the buffer.
+    fn read_leaf(&mut self, subtree: &Subtree) -> Result<(), Error> {
+        debug_assert!(subtree.is_leaf(self.group_len));
+        let len = subtree.len as usize;
+        // The buffer stays empty, as far as reads are concerned, until the
+        // leaf is verified.
+        self.buf_start = 0;
+        self.buf_end = 0;
+        let mut buf = core::mem::take(&mut self.buf);
+        buf.resize(len, 0);
+        let result = self.read_content(&mut buf);
+        self.buf = buf;
+        result?;
+        let chunk_counter = subtree.start / CHUNK_LEN as u64;
+        subtree.verify(&leaf_output(&self.buf, chunk_counter))?;
+        self.buf_end = len;
+        self.position = subtree.start;
+        Ok(())
+    }
+
+    // Read parent nodes until reaching the next leaf, and buffer that. Return
+    // false at the end of the content.
+    fn fill_buf(&mut self) -> Result<bool, Error> {
+        let content_len = match self.content_len {
+            Some(content_len) => content_len,
+            None => self.read_header()?,
+        };
+        while let Some(&subtree) = self.stack.last() {
+            if subtree.is_leaf(self.group_len) {
+                self.read_leaf(&subtree)?;
+                self.stack.pop();
+                return Ok(true);
+            }
+            let (left, right) = self.read_parent(&subtree)?;
+            self.stack.pop();
+            if self.in_encoding(&right, content_len) {
+                self.stack.push(right);
+            }
+            if self.in_encoding(&left, content_len) {
+                self.stack.push(left);
+            }
+        }
+        Ok(false)
+    }
+
+    fn in_encoding(&self, subtree: &Subtree, content_len: u64) -> bool {
+        match self.slice {
+            Some((slice_start, slice_len)) => in_slice(
+                subtree.start,
+                subtree.len,
+                content_len,
+                self.group_len,
+                slice_start,
+                slice_len,
+            ),
+            None => true,
+        }
+    }
+}
+
+impl<R: Read, O: Read> Read for Decoder<R, O> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        if self.buf_start == self.buf_end && !self.fill_buf()? {
+            return Ok(0);
+        }
+        let take = cmp::min(buf.len(), self.buf_end - self.buf_start);
+        buf[..take].copy_from_slice(&self.buf[self.buf_start..][..take]);
+        self.buf_start += take;
+        self.position += take as u64;
+        Ok(take)
+    }
+}
+
+impl<R: Read + Seek, O: Read + Seek> Decoder<R, O> {
+    // Seek the underlying readers to the start of a subtree. In outboard
+    // mode, the content before a subtree is exactly the chunks before it, so
+    // its first parent node is that much earlier in the outboard encoding.
+    fn seek_subtree(&mut self, subtree: &Subtree) -> Result<(), Error> {
+        let offset = match &mut self.outboard {
+            Some(_) if subtree.is_leaf(self.group_len) => subtree.start as u128,
+            Some(outboard) => {
+                let offset = subtree.encoded_offset - subtree.start as u128;
+                outboard.seek(SeekFrom::Start(seek_offset(offset)?))?;
+                return Ok(());
+            }
+            None => subtree.encoded_offset,
+        };
+        self.inner.seek(SeekFrom::Start(seek_offset(offset)?))?;
+        Ok(())
+    }
+
+    // Descend from the root to the leaf containing `target`, verifying the
+    // parent nodes along the way and keeping their right children on the
+    // stack, and buffer that leaf. Targets at or past the end go to the
+    // final leaf.
+    fn seek_to_leaf(&mut self, content_len: u64, target: u64) -> Result<(), Error> {
+        let target_leaf_start = if target < content_len {
+            target / self.group_len * self.group_len
+        } else {
+            (count_leaves(content_len, self.group_len) - 1) * self.group_len
+        };
+        self.buf_start = 0;
+        self.buf_end = 0;
+        self.stack.clear();
+        let mut subtree = self.root(content_len);
+        while !subtree.is_leaf(self.group_len) {
+            self.seek_subtree(&subtree)?;
+            let (left, right) = self.read_parent(&subtree)?;
+            if target_leaf_start < right.start {
+                self.stack.push(right);
+                subtree = left;
+            } else {
+                subtree = right;
+            }
+        }
+        self.seek_subtree(&subtree)?;
+        self.read_leaf(&subtree)
+    }
+
+    fn seek_content(&mut self, pos: SeekFrom) -> Result<u64, Error> {
+        let content_len = match self.content_len {
+            Some(content_len) => content_len,
+            None => {
+                match &mut self.outboard {
+                    Some(outboard) => outboard.seek(SeekFrom::Start(0))?,
+                    None => self.inner.seek(SeekFrom::Start(0))?,
+                };
+                self.read_header()?
+            }
+        };
+        let target = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => add_offset(content_len, offset),
+            SeekFrom::Current(offset) => add_offset(self.position, offset),
+        };
+        let target = target.ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid seek to a negative position",
+            )
+        })?;
+
+        // Seeking within the buffered leaf doesn't need to read anything.
+        let buf_leaf_start = self.position - self.buf_start as u64;
+        if target >= buf_leaf_start && target < buf_leaf_start + self.buf_end as u64 {
+            self.buf_start = (target - buf_leaf_start) as usize;
+            self.position = target;
+            return Ok(target);
+        }
+
+        self.seek_to_leaf(content_len, target)?;
+        if target < content_len {
+            self.buf_start = (target - self.position) as usize;
+        } else {
+            // The final leaf is verified, so the length is authentic, and
+            // there's nothing left to read.
+            self.buf_start = 0;
+            self.buf_end = 0;
+        }
+        self.position = target;
+        Ok(target)
+    }
+}
+
+// An offset that doesn't fit in a u64 is past the end of any real encoding.
+fn seek_offset(offset: u128) -> Result<u64, Error> {
+    u64::try_from(offset).map_err(|_| Error::Truncated)
+}
+
+fn add_offset(position: u64, offset: i64) -> Option<u64> {
+    if offset >= 0 {
+        position.checked_add(offset as u64)
+    } else {
+        position.checked_sub(offset.unsigned_abs())
+    }
+}
+
+impl<R: Read + Seek, O: Read + Seek> Seek for Decoder<R, O> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        Ok(self.seek_content(pos)?)
+    }
+}
+
+/// A reader that decodes a slice extracted by
+/// [`extract_slice`](../encode/fn.extract_slice.html), verifying it against
+/// the root hash as it goes, and returns the content in the slice's range.
+///
+/// The start and length have to be the same ones that the slice was
+/// extracted with. As with [`Decoder`], every parent node and chunk is checked
+/// before any of its content is returned. A range that runs past the end of
+/// the content returns only the content that exists, after verifying the
+/// final chunk.
+///
+/// # Example
+///
+/// ```
+/// use blake3_balanced::bao;
+/// use std::io::{prelude::*, Cursor};
+///
+/// let input = vec![0xab; 100_000];
+/// let (encoded, hash) = bao::encode::encode(&input);
+/// let slice = bao::encode::extract_slice(Cursor::new(&encoded), 50_000, 3_000)?;
+/// assert!(slice.len() < 10_000);
+///
+/// let mut decoder = bao::decode::SliceDecoder::new(&slice[..], &hash, 50_000, 3_000);
+/// let mut output = Vec::new();
+/// decoder.read_to_end(&mut output)?;
+/// assert_eq!(&input[50_000..53_000], &output[..]);
+/// # Ok::<(), std::io::Error>(())
+/// ```
+pub struct SliceDecoder<R> {
+    decoder: Decoder<R>,
+    slice_start: u64,
+    slice_len: u64,
+}
+
+impl<R: Read> SliceDecoder<R> {
+    /// Create a decoder for the given slice, which was extracted with
+    /// `slice_start` and `slice_len` from an encoding that hashes to `hash`.
+    pub fn new(inner: R, hash: &Hash, slice_start: u64, slice_len: u64) -> Self {
+        Self::new_grouped(inner, hash, slice_start, slice_len, 0)
+    }
+
+    /// As [`new`](#method.new), for a slice extracted with
+    /// [`extract_slice_grouped`](../encode/fn.extract_slice_grouped.html).
+    ///
+    /// # Panics
+    ///
+    /// Panics if `chunk_group_log` is greater than
+    /// [`MAX_CHUNK_GROUP_LOG`](../constant.MAX_CHUNK_GROUP_LOG.html).
+    pub fn new_grouped(
+        inner: R,
+        hash: &Hash,
+        slice_start: u64,
+        slice_len: u64,
+        chunk_group_log: u8,
+    ) -> Self {
+        let mut decoder = Decoder::new_grouped(inner, hash, chunk_group_log);
+        decoder.slice = Some((slice_start, slice_len));
+        Self {
+            decoder,
+            slice_start,
+            slice_len,
+        }
+    }
+
+    /// Return the underlying reader.
+    pub fn into_inner(self) -> R {
+        self.decoder.into_inner()
+    }
+}
+
+impl<R: Read> Read for SliceDecoder<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let slice_end = self.slice_start.saturating_add(self.slice_len);
+        let decoder = &mut self.decoder;
+        loop {
+            // Always verify the first leaf, even for an empty read, so that
+            // the length is authenticated.
+            if decoder.buf_start == decoder.buf_end && !decoder.fill_buf()? {
+                return Ok(0);
+            }
+            if decoder.position >= slice_end || buf.is_empty() {
+                return Ok(0);
+            }
+            let available = decoder.buf_end - decoder.buf_start;
+            if decoder.position < self.slice_start {
+                // Skip the part of the first leaf before the slice.
+                let skip = cmp::min(available as u64, self.slice_start - decoder.position);
+                decoder.buf_start += skip as usize;
+                decoder.position += skip;
+                continue;
+            }
+            let take = cmp::min(buf.len(), available);
+            let take = cmp::min(take as u64, slice_end - decoder.position) as usize;
+            buf[..take].copy_from_slice(&decoder.buf[decoder.buf_start..][..take]);
+            decoder.buf_start += take;
+            decoder.position += take as u64;
+            return Ok(take);
+        }
+    }
+}
+
+impl<R> fmt::Debug for SliceDecoder<R> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("SliceDecoder")
+            .field("decoder", &self.decoder)
+            .field("slice_start", &self.slice_start)
+            .field("slice_len", &self.slice_len)
+            .finish()
+    }
+}
+
+impl<R, O> fmt::Debug for Decoder<R, O> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Decoder")
+            .field("hash", &self.hash)
+            .field("outboard", &self.outboard.is_some())
+            .field("content_len", &self.content_len)
+            .field("position", &self.position)
+            .finish()
+    }
+}
@@ -0,0 +1,424 @@
+//! Producing the combined and outboard encodings, and extracting slices.
+
+use super::{group_len, in_slice, leaf_output, HEADER_SIZE, PARENT_SIZE};
+use crate::{
+    left_len, parent_node_output, CVBytes, Hash, Output, CHUNK_LEN, IV, MAX_DEPTH, OUT_LEN,
+};
+use arrayvec::ArrayVec;
+use core::convert::TryFrom;
+use std::io::{self, Read, Seek, SeekFrom};
+
+// The number of leaves in `content_len` bytes of content, with leaves of
+// `group_len` bytes. Even the empty input has one (empty) leaf.
+pub(crate) fn count_leaves(content_len: u64, group_len: u64) -> u64 {
+    if content_len == 0 {
+        1
+    } else {
+        (content_len - 1) / group_len + 1
+    }
+}
+
+// The total size of the parent nodes in the tree for `content_len` bytes of
+// content. A binary tree has one parent fewer than it has leaves.
+pub(crate) fn parents_size(content_len: u64, group_len: u64) -> u128 {
+    (count_leaves(content_len, group_len) - 1) as u128 * PARENT_SIZE as u128
+}
+
+// The size of the pre-order encoding of a subtree, not counting the header.
+pub(crate) fn encoded_subtree_size(content_len: u64, group_len: u64) -> u128 {
+    content_len as u128 + parents_size(content_len, group_len)
+}
+
+/// The size of the combined encoding of `content_len` bytes of content,
+/// including the header. This is a `u128`, because the encoding of the
+/// largest possible input doesn't fit in a `u64`.
+pub fn encoded_size(content_len: u64) -> u128 {
+    encoded_size_grouped(content_len, 0)
+}
+
+/// As [`encoded_size`], for leaves of `2^chunk_group_log` chunks.
+///
+/// # Panics
+///
+/// Panics if `chunk_group_log` is greater than
+/// [`MAX_CHUNK_GROUP_LOG`](../constant.MAX_CHUNK_GROUP_LOG.html).
+pub fn encoded_size_grouped(content_len: u64, chunk_group_log: u8) -> u128 {
+    HEADER_SIZE as u128 + encoded_subtree_size(content_len, group_len(chunk_group_log))
+}
+
+/// Encode `input` in the combined format, and return the encoding along with
+/// the root hash, which is the same as [`hash`](../../fn.hash.html) of the
+/// input.
+pub fn encode(input: impl AsRef<[u8]>) -> (Vec<u8>, Hash) {
+    encode_grouped(input, 0)
+}
+
+/// As [`encode`], for leaves of `2^chunk_group_log` chunks. The root hash is
+/// the same for every `chunk_group_log`, but the encodings aren't.
+///
+/// # Panics
+///
+/// Panics if `chunk_group_log` is greater than
+/// [`MAX_CHUNK_GROUP_LOG`](../constant.MAX_CHUNK_GROUP_LOG.html).
+pub fn encode_grouped(input: impl AsRef<[u8]>, chunk_group_log: u8) -> (Vec<u8>, Hash) {
+    let input = input.as_ref();
+    let size = encoded_size_grouped(input.len() as u64, chunk_group_log);
+    let mut encoded = Vec::with_capacity(size as usize);
+    encoded.extend_from_slice(&(input.len() as u64).to_le_bytes());
+    let group_len = group_len(chunk_group_log);
+    let hash = encode_subtree(input, 0, group_len, false, &mut encoded).root_hash();
+    (encoded, hash)
+}
+
+/// As [`encode`], but reading the input from a
+/// [`std::io::Read`](https://doc.rust-lang.org/std/io/trait.Read.html)
+/// implementation. The whole input is buffered in memory, because the first
+/// parent node depends on the length of the content.
+pub fn encode_from_reader(mut reader: impl Read) -> io::Result<(Vec<u8>, Hash)> {
+    let mut input = Vec::new();
+    reader.read_to_end(&mut input)?;
+    Ok(encode(&input))
+}
+
+/// The size of the outboard encoding of `content_len` bytes of content,
+/// including the header. The parent nodes take 64 bytes for every 1 KiB
+/// chunk, so this is about 1/16 of the content length.
+pub fn outboard_size(content_len: u64) -> u128 {
+    outboard_size_grouped(content_len, 0)
+}
+
+/// As [`outboard_size`], for leaves of `2^chunk_group_log` chunks. Each
+/// increment of `chunk_group_log` halves the size, so that 4, for 16 KiB
+/// leaves, is about 0.4% of the content length.
+///
+/// # Panics
+///
+/// Panics if `chunk_group_log` is greater than
+/// [`MAX_CHUNK_GROUP_LOG`](../constant.MAX_CHUNK_GROUP_LOG.html).
+pub fn outboard_size_grouped(content_len: u64, chunk_group_log: u8) -> u128 {
+    HEADER_SIZE as u128 + parents_size(content_len, group_len(chunk_group_log))
+}
+
+/// Produce the outboard encoding of `input`, and return it along with the
+/// root hash.
+///
+/// The outboard encoding is the combined encoding without the content: the
+/// length header followed by the parent nodes in pre-order. It's meant to be
+/// stored next to content that's kept as it is, and read along with it by
+/// [`Decoder::new_outboard`](../decode/struct.Decoder.html#method.new_outboard).
+/// It's identical to the outboard encoding of the `bao` crate.
+pub fn outboard(input: impl AsRef<[u8]>) -> (Vec<u8>, Hash) {
+    outboard_grouped(input, 0)
+}
+
+/// As [`outboard`], for leaves of `2^chunk_group_log` chunks.
+///
+/// # Panics
+///
+/// Panics if `chunk_group_log` is greater than
+/// [`MAX_CHUNK_GROUP_LOG`](../constant.MAX_CHUNK_GROUP_LOG.html).
+pub fn outboard_grouped(input: impl AsRef<[u8]>, chunk_group_log: u8) -> (Vec<u8>, Hash) {
+    let input = input.as_ref();
+    let size = outboard_size_grouped(input.len() as u64, chunk_group_log);
+    let mut outboard = Vec::with_capacity(size as usize);
+    outboard.extend_from_slice(&(input.len() as u64).to_le_bytes());
+    let group_len = group_len(chunk_group_log);
+    let hash = encode_subtree(input, 0, group_len, true, &mut outboard).root_hash();
+    (outboard, hash)
+}
+
+/// As [`outboard`], but reading the input from a
+/// [`std::io::Read`](https://doc.rust-lang.org/std/io/trait.Read.html)
+/// implementation. Unlike [`encode_from_reader`], this doesn't buffer the
+/// input, only the parent nodes.
+pub fn outboard_from_reader(reader: impl Read) -> io::Result<(Vec<u8>, Hash)> {
+    outboard_from_reader_grouped(reader, 0)
+}
+
+/// As [`outboard_from_reader`], for leaves of `2^chunk_group_log` chunks.
+///
+/// # Panics
+///
+/// Panics if `chunk_group_log` is greater than
+/// [`MAX_CHUNK_GROUP_LOG`](../constant.MAX_CHUNK_GROUP_LOG.html).
+pub fn outboard_from_reader_grouped(
+    mut reader: impl Read,
+    chunk_group_log: u8,
+) -> io::Result<(Vec<u8>, Hash)> {
+    let group_len = group_len(chunk_group_log);
+    // Parent nodes are produced in post-order as the chaining value stack
+    // merges, and rearranged into pre-order at the end, once the length is
+    // known. Each leaf is only hashed once the next one has been read,
+    // because the last leaf is special when it's the root.
+    let mut post_order = Vec::new();
+    let mut cv_stack = ArrayVec::<CVBytes, { MAX_DEPTH + 1 }>::new();
+    let mut content_len = 0;
+    let mut leaf = vec![0; group_len as usize];
+    let mut leaf_len = read_leaf(&mut reader, &mut leaf)?;
+    let mut next_leaf = vec![0; group_len as usize];
+    let mut leaf_counter = 0;
+    loop {
+        let next_leaf_len = read_leaf(&mut reader, &mut next_leaf)?;
+        let chunk_counter = leaf_counter << chunk_group_log;
+        let mut output = leaf_output(&leaf[..leaf_len], chunk_counter);
+        content_len += leaf_len as u64;
+        if next_leaf_len == 0 {
+            // This was the last leaf, so merge everything into the root.
+            while let Some(left_cv) = cv_stack.pop() {
+                let right_cv = output.chaining_value();
+                post_order.extend_from_slice(&left_cv);
+                post_order.extend_from_slice(&right_cv);
+                output = parent_node_output(&left_cv, &right_cv, IV, 0);
+            }
+            let size = outboard_size_grouped(content_len, chunk_group_log);
+            let mut outboard = Vec::with_capacity(size as usize);
+            outboard.extend_from_slice(&content_len.to_le_bytes());
+            post_to_pre_order(&post_order, content_len, group_len, &mut outboard);
+            return Ok((outboard, output.root_hash()));
+        }
+        // More input is coming, so none of these merges is the root. Every
+        // trailing zero bit of the new leaf count completes a subtree.
+        let mut cv = output.chaining_value();
+        leaf_counter += 1;
+        let mut total_leaves = leaf_counter;
+        while total_leaves & 1 == 0 {
+            let left_cv = cv_stack.pop().unwrap();
+            post_order.extend_from_slice(&left_cv);
+            post_order.extend_from_slice(&cv);
+            cv = parent_node_output(&left_cv, &cv, IV, 0).chaining_value();
+            total_leaves >>= 1;
+        }
+        cv_stack.push(cv);
+        core::mem::swap(&mut leaf, &mut next_leaf);
+        leaf_len = next_leaf_len;
+    }
+}
+
+// Fill `leaf` from the reader, stopping short only at EOF.
+fn read_leaf(reader: &mut impl Read, leaf: &mut [u8]) -> io::Result<usize> {
+    let mut len = 0;
+    while len < leaf.len() {
+        match reader.read(&mut leaf[len..]) {
+            Ok(0) => break,
+            Ok(n) => len += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(len)
+}
+
+// Append the parent nodes of a subtree, given in post-order, to `pre_order`.
+// In post-order, a parent comes after the parents of its left subtree and
+// then its right subtree.
+fn post_to_pre_order(post_order: &[u8], content_len: u64, group_len: u64, pre_order: &mut Vec<u8>) {
+    if content_len <= group_len {
+        debug_assert!(post_order.is_empty());
+        return;
+    }
+    let (children, parent) = post_order.split_at(post_order.len() - PARENT_SIZE);
+    pre_order.extend_from_slice(parent);
+    let left_len = super::left_len(content_len);
+    let (left, right) = children.split_at(parents_size(left_len, group_len) as usize);
+    post_to_pre_order(left, left_len, group_len, pre_order);
+    post_to_pre_order(right, content_len - left_len, group_len, pre_order);
+}
+
+// Append the pre-order encoding of a subtree to `encoded`, and return its
+// Output. The caller decides whether that becomes a chaining value or, at the
+// top of the tree, the root hash. The outboard encoding leaves out the leaves.
+fn encode_subtree(
+    input: &[u8],
+    chunk_counter: u64,
+    group_len: u64,
+    outboard: bool,
+    encoded: &mut Vec<u8>,
+) -> Output {
+    if input.len() as u64 <= group_len {
+        if !outboard {
+            encoded.extend_from_slice(input);
+        }
+        return leaf_output(input, chunk_counter);
+    }
+
+    let (left, right) = input.split_at(left_len(input.len()));
+    let right_chunk_counter = chunk_counter + (left.len() / CHUNK_LEN) as u64;
+
+    // The parent node comes first, but it holds the chaining values of both
+    // children, so leave room for it and fill it in afterwards.
+    let parent_start = encoded.len();
+    encoded.extend_from_slice(&[0; PARENT_SIZE]);
+    let left_cv =
+        encode_subtree(left, chunk_counter, group_len, outboard, encoded).chaining_value();
+    let right_cv =
+        encode_subtree(right, right_chunk_counter, group_len, outboard, encoded).chaining_value();
+    encoded[parent_start..][..OUT_LEN].copy_from_slice(&left_cv);
+    encoded[parent_start + OUT_LEN..][..OUT_LEN].copy_from_slice(&right_cv);
+    parent_node_output(&left_cv, &right_cv, IV, 0)
+}
+
+/// Extract the slice of a combined encoding that covers `slice_len` bytes of
+/// content starting at `slice_start`, to be read by a
+/// [`SliceDecoder`](../decode/struct.SliceDecoder.html).
+///
+/// The slice is the length header, followed by the parent nodes and chunks
+/// that the range touches, in the same pre-order as the full encoding, so
+/// that it can be verified against the root hash without any of the content
+/// outside of it. The range doesn't have to fall on chunk boundaries, and the
+/// chunks at either end are included whole. Every slice includes at least
+/// one chunk, to verify the length: an empty range is treated as one byte
+/// long, and a range that starts at or past the end of the content includes
+/// the final chunk. The format is identical to the `bao` crate's.
+///
+/// This doesn't verify anything, and only reads the nodes it needs.
+pub fn extract_slice(
+    encoded: impl Read + Seek,
+    slice_start: u64,
+    slice_len: u64,
+) -> io::Result<Vec<u8>> {
+    extract_slice_grouped(encoded, slice_start, slice_len, 0)
+}
+
+/// As [`extract_slice`], for an encoding with leaves of `2^chunk_group_log`
+/// chunks. The slice includes the whole leaves at either end of the range.
+///
+/// # Panics
+///
+/// Panics if `chunk_group_log` is greater than
+/// [`MAX_CHUNK_GROUP_LOG`](../constant.MAX_CHUNK_GROUP_LOG.html).
+pub fn extract_slice_grouped(
+    encoded: impl Read + Seek,
+    slice_start: u64,
+    slice_len: u64,
+    chunk_group_log: u8,
+) -> io::Result<Vec<u8>> {
+    let group_len = group_len(chunk_group_log);
+    SliceExtractor::new(
+        encoded,
+        None::<io::Empty>,
+        slice_start,
+        slice_len,
+        group_len,
+    )
+    .extract()
+}
+
+/// As [`extract_slice`], but extracting from content and its outboard
+/// encoding. The slice is the same as if it came from the combined encoding.
+pub fn extract_slice_outboard(
+    content: impl Read + Seek,
+    outboard: impl Read + Seek,
+    slice_start: u64,
+    slice_len: u64,
+) -> io::Result<Vec<u8>> {
+    extract_slice_outboard_grouped(content, outboard, slice_start, slice_len, 0)
+}
+
+/// As [`extract_slice_outboard`], for leaves of `2^chunk_group_log` chunks.
+///
+/// # Panics
+///
+/// Panics if `chunk_group_log` is greater than
+/// [`MAX_CHUNK_GROUP_LOG`](../constant.MAX_CHUNK_GROUP_LOG.html).
+pub fn extract_slice_outboard_grouped(
+    content: impl Read + Seek,
+    outboard: impl Read + Seek,
+    slice_start: u64,
+    slice_len: u64,
+    chunk_group_log: u8,
+) -> io::Result<Vec<u8>> {
+    let group_len = group_len(chunk_group_log);
+    SliceExtractor::new(content, Some(outboard), slice_start, slice_len, group_len).extract()
+}
+
+struct SliceExtractor<C, O> {
+    // The combined encoding, or the content in outboard mode.
+    inner: C,
+    outboard: Option<O>,
+    content_len: u64,
+    slice_start: u64,
+    slice_len: u64,
+    group_len: u64,
+    slice: Vec<u8>,
+}
+
+impl<C: Read + Seek, O: Read + Seek> SliceExtractor<C, O> {
+    fn new(
+        inner: C,
+        outboard: Option<O>,
+        slice_start: u64,
+        slice_len: u64,
+        group_len: u64,
+    ) -> Self {
+        Self {
+            inner,
+            outboard,
+            content_len: 0,
+            slice_start,
+            slice_len,
+            group_len,
+            slice: Vec::new(),
+        }
+    }
+
+    fn extract(mut self) -> io::Result<Vec<u8>> {
+        self.copy(false, 0, 0, HEADER_SIZE)?;
+        self.content_len =
+            u64::from_le_bytes(*crate::platform::array_ref!(self.slice, 0, HEADER_SIZE));
+        self.extract_subtree(0, self.content_len, HEADER_SIZE as u128)?;
+        Ok(self.slice)
+    }
+
+    // Append the nodes of a subtree that the slice needs, given where the
+    // subtree starts in the content and in the combined encoding.
+    fn extract_subtree(&mut self, start: u64, len: u64, encoded_offset: u128) -> io::Result<()> {
+        if !in_slice(
+            start,
+            len,
+            self.content_len,
+            self.group_len,
+            self.slice_start,
+            self.slice_len,
+        ) {
+            return Ok(());
+        }
+        if len <= self.group_len {
+            return self.copy(true, start, encoded_offset, len as usize);
+        }
+        self.copy(false, start, encoded_offset, PARENT_SIZE)?;
+        let left_len = super::left_len(len);
+        let left_offset = encoded_offset + PARENT_SIZE as u128;
+        let right_offset = left_offset + encoded_subtree_size(left_len, self.group_len);
+        self.extract_subtree(start, left_len, left_offset)?;
+        self.extract_subtree(start + left_len, len - left_len, right_offset)
+    }
+
+    // Append `len` bytes of a leaf, or of the header or a parent node, to the
+    // slice. In outboard mode, the tree comes from the outboard encoding, and
+    // the content before a node is exactly the leaves before it.
+    fn copy(
+        &mut self,
+        is_leaf: bool,
+        start: u64,
+        encoded_offset: u128,
+        len: usize,
+    ) -> io::Result<()> {
+        let (reader, offset): (&mut dyn ReadSeek, u128) = match &mut self.outboard {
+            Some(_) if is_leaf => (&mut self.inner, start as u128),
+            Some(outboard) => (outboard, encoded_offset - start as u128),
+            None => (&mut self.inner, encoded_offset),
+        };
+        // An offset that doesn't fit in a u64 is past the end of any real
+        // encoding.
+        let offset = u64::try_from(offset)
+            .map_err(|_| io::Error::new(io::ErrorKind::UnexpectedEof, "encoding truncated"))?;
+        reader.seek(SeekFrom::Start(offset))?;
+        let slice_len = self.slice.len();
+        self.slice.resize(slice_len + len, 0);
+        reader.read_exact(&mut self.slice[slice_len..])
+    }
+}
+
+trait ReadSeek: Read + Seek {}
+
+impl<T: Read + Seek> ReadSeek for T {}
@@ -0,0 +1,448 @@
+//! A command line utility for calculating BLAKE3 hashes, compatible with the
+//! output of [`b3sum`](https://crates.io/crates/b3sum).
+//!
+//! The argument parsing is done by hand rather than with `clap`, to keep the
+//! compile time of this binary close to that of the library itself.
+
+use blake3_balanced::checksum::{self, ChecksumLine};
+use blake3_balanced::{Hash, Hasher, OutputReader, KEY_LEN, OUT_LEN};
+use std::ffi::OsString;
+use std::io::{self, BufRead, Read, Write};
+use std::path::{Path, PathBuf};
+use std::{cmp, fmt, process};
+
+const NAME: &str = "b3sum";
+
+const USAGE_HEAD: &str = "\
+Print or check BLAKE3 checksums.
+
+With no FILE, or when FILE is -, read standard input.
+
+Usage: b3sum [OPTIONS] [FILE]...
+
+Options:
+      --keyed                 Use the keyed mode, reading the 32-byte key from stdin
+      --derive-key <CONTEXT>  Use the key derivation mode, with the given context string
+  -l, --length <LEN>          The number of output bytes, before hex encoding [default: 32]
+";
+
+const USAGE_RAYON: &str = "      --num-threads <NUM>     The maximum number of threads to use
+";
+
+const USAGE_TAIL: &str = "      --no-mmap               Disable memory mapping
+      --no-names              Omit filenames in the output
+      --raw                   Write raw output bytes to stdout, rather than hex
+  -c, --check                 Read BLAKE3 sums from the FILEs and check them
+      --quiet                 Skip printing OK for each checked file
+  -h, --help                  Print help
+  -V, --version               Print version
+";
+
+#[derive(Debug)]
+struct UsageError(String);
+
+impl fmt::Display for UsageError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+#[derive(Default)]
+struct Args {
+    files: Vec<PathBuf>,
+    keyed: bool,
+    derive_key: Option<String>,
+    length: Option<u64>,
+    #[cfg(feature = "rayon")]
+    num_threads: Option<usize>,
+    no_mmap: bool,
+    no_names: bool,
+    raw: bool,
+    check: bool,
+    quiet: bool,
+}
+
+enum Command {
+    Hash(Args),
+    Help,
+    Version,
+}
+
+impl Args {
+    fn parse(mut raw_args: impl Iterator<Item = OsString>) -> Result<Command, UsageError> {
+        let mut args = Args::default();
+        let mut only_files = false;
+        while let Some(arg) = raw_args.next() {
+            if only_files || arg == "-" || !arg.to_string_lossy().starts_with('-') {
+                args.files.push(PathBuf::from(arg));
+                continue;
+            }
+            let arg = arg
+                .into_string()
+                .map_err(|arg| UsageError(format!("invalid option {:?}", arg)))?;
+            // Support both `--length 64` and `--length=64`.
+            let (name, mut inline_value) = match arg.find('=') {
+                Some(i) if arg.starts_with("--") => (&arg[..i], Some(arg[i + 1..].to_string())),
+                _ => (&arg[..], None),
+            };
+            let mut value = |name: &str| -> Result<String, UsageError> {
+                match inline_value.take().map(OsString::from).or_else(|| raw_args.next()) {
+                    Some(value) => value
+                        .into_string()
+                        .map_err(|value| UsageError(format!("invalid value {:?} for {}", value, name))),
+                    None => Err(UsageError(format!("a value is required for {}", name))),
+                }
+            };
+            match name {
+                "--" => only_files = true,
+                "-h" | "--help" => return Ok(Command::Help),
+                "-V" | "--version" => return Ok(Command::Version),
+                "--keyed" => args.keyed = true,
+                "--derive-key" => args.derive_key = Some(value(name)?),
+                "-l" | "--length" => {
+                    let length = value(name)?;
+                    args.length = Some(length.parse().map_err(|_| {
+                        UsageError(format!("invalid value {:?} for {}", length, name))
+                    })?);
+                }
+                #[cfg(feature = "rayon")]
+                "--num-threads" => {
+                    let num_threads = value(name)?;
+                    args.num_threads = Some(num_threads.parse().map_err(|_| {
+                        UsageError(format!("invalid value {:?} for {}", num_threads, name))
+                    })?);
+                }
+                "--no-mmap" => args.no_mmap = true,
+                "--no-names" => args.no_names = true,
+                "--raw" => args.raw = true,
+                "-c" | "--check" => args.check = true,
+                "--quiet" => args.quiet = true,
+                _ => return Err(UsageError(format!("unexpected argument {:?}", name))),
+            }
+            if inline_value.is_some() {
+                return Err(UsageError(format!("unexpected value for {}", name)));
+            }
+        }
+
+        if args.keyed && args.derive_key.is_some() {
+            return Err(UsageError(
+                "--keyed and --derive-key can't be used together".to_string(),
+            ));
+        }
+        if args.check {
+            let conflicts = [
+                ("--keyed", args.keyed),
+                ("--derive-key", args.derive_key.is_some()),
+                ("--length", args.length.is_some()),
+                ("--raw", args.raw),
+                ("--no-names", args.no_names),
+            ];
+            if let Some((name, _)) = conflicts.iter().find(|(_, is_set)| *is_set) {
+                return Err(UsageError(format!("{} can't be used with --check", name)));
+            }
+        } else if args.quiet {
+            return Err(UsageError("--quiet can only be used with --check".to_string()));
+        }
+        if args.raw && args.files.len() > 1 {
+            return Err(UsageError(
+                "only one filename can be provided when using --raw".to_string(),
+            ));
+        }
+        if args.files.is_empty() {
+            args.files.push(PathBuf::from("-"));
+        }
+        Ok(Command::Hash(args))
+    }
+
+    fn new_hasher(&self) -> io::Result<Hasher> {
+        if self.keyed {
+            let key = read_key_from_stdin()?;
+            Ok(Hasher::new_keyed(&key))
+        } else if let Some(context) = &self.derive_key {
+            Ok(Hasher::new_derive_key(context))
+        } else {
+            Ok(Hasher::new())
+        }
+    }
+}
+
+// Stdin can only be read once, and in keyed mode it holds the key.
+fn read_key_from_stdin() -> io::Result<[u8; KEY_LEN]> {
+    let mut bytes = Vec::with_capacity(KEY_LEN + 1);
+    let n = io::stdin()
+        .lock()
+        .take(KEY_LEN as u64 + 1)
+        .read_to_end(&mut bytes)?;
+    if n != KEY_LEN {
+        let message = if n < KEY_LEN {
+            format!("expected {} key bytes from stdin, found {}", KEY_LEN, n)
+        } else {
+            format!("read more than {} key bytes from stdin", KEY_LEN)
+        };
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, message));
+    }
+    let mut key = [0; KEY_LEN];
+    key.copy_from_slice(&bytes);
+    Ok(key)
+}
+
+fn hash_path(args: &Args, base_hasher: &Hasher, path: &Path) -> io::Result<OutputReader> {
+    let mut hasher = base_hasher.clone();
+    if path == Path::new("-") {
+        if args.keyed {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "cannot open `-` in keyed mode",
+            ));
+        }
+        hasher.update_reader(io::stdin().lock())?;
+    } else if args.no_mmap {
+        hasher.update_reader(std::fs::File::open(path)?)?;
+    } else {
+        update_file(&mut hasher, path)?;
+    }
+    Ok(hasher.finalize_xof())
+}
+
+#[cfg(all(feature = "mmap", feature = "rayon"))]
+fn update_file(hasher: &mut Hasher, path: &Path) -> io::Result<()> {
+    hasher.update_mmap_rayon(path).map(drop)
+}
+
+#[cfg(all(feature = "mmap", not(feature = "rayon")))]
+fn update_file(hasher: &mut Hasher, path: &Path) -> io::Result<()> {
+    hasher.update_mmap(path).map(drop)
+}
+
+#[cfg(not(feature = "mmap"))]
+fn update_file(hasher: &mut Hasher, path: &Path) -> io::Result<()> {
+    hasher.update_reader(std::fs::File::open(path)?).map(drop)
+}
+
+// Paths are printed lossily, and escaped if they contain backslashes or
+// newlines. See the checksum module.
+fn filepath_to_string(path: &Path) -> (String, bool) {
+    let path = path.to_string_lossy();
+    match checksum::escape_path(&path) {
+        Some(escaped) => (escaped, true),
+        None => (path.into_owned(), false),
+    }
+}
+
+fn write_hex_output(out: &mut impl Write, mut output: OutputReader, len: u64) -> io::Result<()> {
+    // Encoding multiples of the block size is most efficient.
+    let mut block = [0; 64];
+    let mut remaining = len;
+    while remaining > 0 {
+        output.fill(&mut block);
+        let take = cmp::min(remaining, block.len() as u64) as usize;
+        for byte in &block[..take] {
+            write!(out, "{:02x}", byte)?;
+        }
+        remaining -= take as u64;
+    }
+    Ok(())
+}
+
+fn write_raw_output(out: &mut impl Write, output: OutputReader, len: u64) -> io::Result<()> {
+    io::copy(&mut output.take(len), out).map(drop)
+}
+
+fn hash_all(args: &Args) -> io::Result<bool> {
+    let base_hasher = args.new_hasher()?;
+    let len = args.length.unwrap_or(OUT_LEN as u64);
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    let mut did_error = false;
+    for path in &args.files {
+        let output = match hash_path(args, &base_hasher, path) {
+            Ok(output) => output,
+            Err(e) => {
+                did_error = true;
+                out.flush()?;
+                eprintln!("{}: {}: {}", NAME, path.to_string_lossy(), e);
+                continue;
+            }
+        };
+        if args.raw {
+            write_raw_output(&mut out, output, len)?;
+            continue;
+        }
+        let (path_string, is_escaped) = filepath_to_string(path);
+        if is_escaped && !args.no_names {
+            out.write_all(b"\\")?;
+        }
+        write_hex_output(&mut out, output, len)?;
+        if !args.no_names {
+            write!(out, "  {}", path_string)?;
+        }
+        writeln!(out)?;
+    }
+    out.flush()?;
+    Ok(did_error)
+}
+
+// Check a single line of a checkfile, printing the result to stdout. Parse
+// errors go to stderr. Returns whether the line checked out.
+fn check_one_line(
+    args: &Args,
+    out: &mut impl Write,
+    line: &[u8],
+    checkfile_is_stdin: bool,
+) -> io::Result<bool> {
+    let line = match ChecksumLine::parse_bytes(line) {
+        Ok(line) => line,
+        Err(e) => {
+            out.flush()?;
+            eprintln!("{}: {}", NAME, e);
+            return Ok(false);
+        }
+    };
+    let display_path = line.display_path();
+    let path = Path::new(&line.path);
+    // When the checkfile is stdin, we're holding the stdin lock, and locking
+    // it again to hash `-` would never return.
+    let output = if checkfile_is_stdin && path == Path::new("-") {
+        Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "stdin is the checkfile",
+        ))
+    } else {
+        hash_path(args, &Hasher::new(), path)
+    };
+    let mut output = match output {
+        Ok(output) => output,
+        Err(e) => {
+            writeln!(out, "{}: FAILED ({})", display_path, e)?;
+            return Ok(false);
+        }
+    };
+    let mut found = [0; OUT_LEN];
+    output.fill(&mut found);
+    // This is a constant-time comparison.
+    if line.hash == Hash::from(found) {
+        if !args.quiet {
+            writeln!(out, "{}: OK", display_path)?;
+        }
+        Ok(true)
+    } else {
+        writeln!(out, "{}: FAILED", display_path)?;
+        Ok(false)
+    }
+}
+
+fn check_one_checkfile(args: &Args, out: &mut impl Write, path: &Path) -> io::Result<u64> {
+    let stdin = io::stdin();
+    let checkfile_is_stdin = path == Path::new("-");
+    let mut reader: Box<dyn BufRead> = if checkfile_is_stdin {
+        Box::new(stdin.lock())
+    } else {
+        Box::new(io::BufReader::new(std::fs::File::open(path)?))
+    };
+    let mut files_failed = 0;
+    // Read bytes rather than using BufRead::lines, which would give up on the
+    // rest of the file at the first line that isn't UTF-8.
+    let mut line = Vec::new();
+    loop {
+        line.clear();
+        if reader.read_until(b'\n', &mut line)? == 0 {
+            break;
+        }
+        let checked = check_one_line(args, out, &line, checkfile_is_stdin)?;
+        if !checked {
+            files_failed += 1;
+        }
+    }
+    Ok(files_failed)
+}
+
+fn check_all(args: &Args) -> io::Result<bool> {
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    let mut did_error = false;
+    let mut files_failed = 0;
+    for path in &args.files {
+        match check_one_checkfile(args, &mut out, path) {
+            Ok(n) => files_failed += n,
+            Err(e) => {
+                did_error = true;
+                out.flush()?;
+                eprintln!("{}: {}: {}", NAME, path.to_string_lossy(), e);
+            }
+        }
+    }
+    out.flush()?;
+    if files_failed > 0 {
+        let plural = if files_failed == 1 { "" } else { "s" };
+        eprintln!(
+            "{}: WARNING: {} computed checksum{} did NOT match",
+            NAME, files_failed, plural
+        );
+        did_error = true;
+    }
+    Ok(did_error)
+}
+
+fn hash_or_check_all(args: &Args) -> io::Result<bool> {
+    if args.check {
+        check_all(args)
+    } else {
+        hash_all(args)
+    }
+}
+
+#[cfg(feature = "rayon")]
+fn run(args: &Args) -> io::Result<bool> {
+    let mut builder = rayon::ThreadPoolBuilder::new();
+    if let Some(num_threads) = args.num_threads {
+        builder = builder.num_threads(num_threads);
+    }
+    let pool = builder
+        .build()
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    pool.install(|| hash_or_check_all(args))
+}
+
+#[cfg(not(feature = "rayon"))]
+fn run(args: &Args) -> io::Result<bool> {
+    hash_or_check_all(args)
+}
+
+fn usage() -> String {
+    let mut usage = String::from(USAGE_HEAD);
+    if cfg!(feature = "rayon") {
+        usage.push_str(USAGE_RAYON);
+    }
+    usage.push_str(USAGE_TAIL);
+    usage
+}
+
+fn main() {
+    let args = match Args::parse(std::env::args_os().skip(1)) {
+        Ok(Command::Hash(args)) => args,
+        Ok(Command::Help) => {
+            print!("{}", usage());
+            return;
+        }
+        Ok(Command::Version) => {
+            println!("{} {}", NAME, env!("CARGO_PKG_VERSION"));
+            return;
+        }
+        Err(e) => {
+            eprintln!("{}: error: {}\n\nFor more information, try '--help'.", NAME, e);
+            process::exit(2);
+        }
+    };
+    match run(&args) {
+        Ok(false) => {}
+        Ok(true) => process::exit(1),
+        // A closed stdout, for example when piping into `head`, isn't worth
+        // reporting.
+        Err(e) if e.kind() == io::ErrorKind::BrokenPipe => process::exit(1),
+        Err(e) => {
+            eprintln!("{}: {}", NAME, e);
+            process::exit(1);
+        }
+    }
+}
+
@@ -0,0 +1,280 @@
+//! Reading and writing checksum files in the format used by `b3sum`.
+//!
+//! Each line of a checksum file is a hex hash, two spaces, and a path, like the
+//! output of `sha256sum` and similar tools:
+//!
+//! ```text
+//! ea8f163db38682925e4491c5e58d4bb3506ef8c14eb78a86e908c5624a67200f  hello.txt
+//! ```
+//!
+//! Paths containing backslashes, newlines, or carriage returns would make that
+//! format ambiguous. Those characters are escaped as `\\`, `\n`, and `\r`, and
+//! the whole line is prefixed with a single backslash to mark it as escaped.
+//! This matches the convention of `md5sum` and `b3sum`.
+//!
+//! This module requires the `std` Cargo feature, which is enabled by default.
+//!
+//! # Example
+//!
+//! ```
+//! use blake3_balanced::checksum::ChecksumLine;
+//!
+//! let line = ChecksumLine {
+//!     hash: blake3_balanced::hash(b"hello"),
+//!     path: "new\nline.txt".into(),
+//! };
+//! let text = line.to_string();
+//! assert!(text.starts_with("\\ea8f163d"));
+//! assert!(text.ends_with("  new\\nline.txt"));
+//!
+//! let parsed: ChecksumLine = text.parse()?;
+//! assert_eq!(parsed, line);
+//! # Ok::<(), blake3_balanced::checksum::ParseError>(())
+//! ```
+
+use crate::{Hash, OUT_LEN};
+use std::borrow::Cow;
+use std::fmt;
+use std::str::FromStr;
+
+/// One line of a checksum file: an expected hash and the path it belongs to.
+///
+/// The `path` is stored unescaped. [`Display`](#impl-Display) escapes it again
+/// when necessary, and [`parse`](#method.parse) unescapes it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ChecksumLine {
+    /// The expected hash of the file's contents.
+    pub hash: Hash,
+    /// The path of the file, without any escaping.
+    pub path: String,
+}
+
+impl ChecksumLine {
+    /// Parse one line of a checksum file. A trailing `\n` or `\r\n` is
+    /// ignored. Hashes are written in lowercase hex, but uppercase is accepted
+    /// too.
+    pub fn parse(mut line: &str) -> Result<Self, ParseError> {
+        line = line.trim_end_matches(['\n', '\r']);
+        if line.is_empty() {
+            return Err(ParseError::EmptyLine);
+        }
+        let is_escaped = line.starts_with('\\');
+        if is_escaped {
+            line = &line[1..];
+        }
+
+        // The front of the line must be a hash of the usual length, followed
+        // by two spaces and at least one byte of path.
+        let hash_hex_len = 2 * OUT_LEN;
+        let prefix_len = hash_hex_len + 2;
+        if line.len() <= prefix_len {
+            return Err(ParseError::ShortLine);
+        }
+        if !line.is_char_boundary(prefix_len) || !line[..prefix_len].is_ascii() {
+            return Err(ParseError::NonAsciiPrefix);
+        }
+        if &line[hash_hex_len..prefix_len] != "  " {
+            return Err(ParseError::InvalidSpace);
+        }
+        let hash = Hash::from_hex(&line[..hash_hex_len]).map_err(|_| ParseError::InvalidHex)?;
+
+        let path = &line[prefix_len..];
+        let path = if is_escaped {
+            unescape_path(path)?
+        } else {
+            path.to_string()
+        };
+        if path.contains('\0') {
+            return Err(ParseError::NullCharacter);
+        }
+        Ok(Self { hash, path })
+    }
+
+    /// Parse one line of a checksum file from bytes, as with
+    /// [`parse`](#method.parse). A line that isn't UTF-8 is an
+    /// [`InvalidUtf8`](enum.ParseError.html#variant.InvalidUtf8) error.
+    pub fn parse_bytes(line: &[u8]) -> Result<Self, ParseError> {
+        match std::str::from_utf8(line) {
+            Ok(line) => Self::parse(line),
+            Err(_) => Err(ParseError::InvalidUtf8),
+        }
+    }
+
+    /// The path as it appears in a checksum file, including the leading
+    /// backslash that marks an escaped line.
+    pub fn display_path(&self) -> Cow<'_, str> {
+        match escape_path(&self.path) {
+            Some(escaped) => Cow::Owned(format!("\\{}", escaped)),
+            None => Cow::Borrowed(&self.path),
+        }
+    }
+}
+
+/// Formats the line without a trailing newline.
+impl fmt::Display for ChecksumLine {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match escape_path(&self.path) {
+            Some(escaped) => write!(f, "\\{}  {}", self.hash, escaped),
+            None => write!(f, "{}  {}", self.hash, self.path),
+        }
+    }
+}
+
+impl FromStr for ChecksumLine {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        ChecksumLine::parse(s)
+    }
+}
+
+/// Escape backslashes, newlines, and carriage returns in a path. Returns
+/// `None` if the path doesn't need escaping.
+///
+/// When a path is escaped, the line it's written on must start with a
+/// backslash. [`ChecksumLine`](struct.ChecksumLine.html) takes care of that,
+/// but callers that write lines themselves, for example with extended output
+/// lengths, have to add it.
+pub fn escape_path(path: &str) -> Option<String> {
+    if path.contains(['\\', '\n', '\r']) {
+        let escaped = path
+            .replace('\\', "\\\\")
+            .replace('\n', "\\n")
+            .replace('\r', "\\r");
+        Some(escaped)
+    } else {
+        None
+    }
+}
+
+/// Reverse [`escape_path`](fn.escape_path.html). Any backslash that isn't
+/// followed by `\`, `n`, or `r` is an error.
+pub fn unescape_path(path: &str) -> Result<String, ParseError> {
+    let mut unescaped = String::with_capacity(path.len());
+    let mut chars = path.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('\\') => unescaped.push('\\'),
+            Some('n') => unescaped.push('\n'),
+            Some('r') => unescaped.push('\r'),
+            _ => return Err(ParseError::InvalidEscape),
+        }
+    }
+    Ok(unescaped)
+}
+
+/// The error type for [`ChecksumLine::parse`](struct.ChecksumLine.html#method.parse)
+/// and [`ChecksumLine::parse_bytes`](struct.ChecksumLine.html#method.parse_bytes).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ParseError {
+    /// The line wasn't valid UTF-8.
+    InvalidUtf8,
+    /// The line was empty.
+    EmptyLine,
+    /// The line was too short to hold a hash, two spaces, and a path.
+    ShortLine,
+    /// The hash or the following spaces contained non-ASCII characters.
+    NonAsciiPrefix,
+    /// The hash wasn't followed by exactly two spaces.
+    InvalidSpace,
+    /// The hash wasn't valid hexadecimal.
+    InvalidHex,
+    /// An escaped path contained a backslash escape other than `\\`, `\n`, or
+    /// `\r`.
+    InvalidEscape,
+    /// The path contained a null character.
+    NullCharacter,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            ParseError::InvalidUtf8 => "invalid UTF-8",
+            ParseError::EmptyLine => "empty line",
+            ParseError::ShortLine => "short line",
+            ParseError::NonAsciiPrefix => "non-ASCII prefix",
+            ParseError::InvalidSpace => "invalid space",
+            ParseError::InvalidHex => "invalid hex",
+            ParseError::InvalidEscape => "invalid backslash escape",
+            ParseError::NullCharacter => "null character in path",
+        })
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HEX: &str = "ea8f163db38682925e4491c5e58d4bb3506ef8c14eb78a86e908c5624a67200f";
+
+    #[test]
+    fn roundtrip() {
+        for &path in &["a", "with space", "back\\slash", "new\nline", "cr\r", "\\\\n"] {
+            let line = ChecksumLine {
+                hash: crate::hash(path.as_bytes()),
+                path: path.to_string(),
+            };
+            let text = line.to_string();
+            assert_eq!(text.starts_with('\\'), escape_path(path).is_some());
+            assert!(!text.contains('\n'));
+            assert_eq!(ChecksumLine::parse(&text), Ok(line.clone()));
+            assert_eq!(ChecksumLine::parse(&(text.clone() + "\r\n")), Ok(line));
+        }
+    }
+
+    #[test]
+    fn parse() {
+        let line = ChecksumLine::parse(&format!("{}  hello.txt\n", HEX)).unwrap();
+        assert_eq!(line.hash, crate::hash(b"hello"));
+        assert_eq!(line.path, "hello.txt");
+
+        // Only escaped lines are unescaped.
+        let line = ChecksumLine::parse(&format!("{}  a\\nb", HEX)).unwrap();
+        assert_eq!(line.path, "a\\nb");
+        assert_eq!(line.display_path(), "\\a\\\\nb");
+        let line = ChecksumLine::parse(&format!("\\{}  a\\nb", HEX)).unwrap();
+        assert_eq!(line.path, "a\nb");
+        assert_eq!(line.display_path(), "\\a\\nb");
+    }
+
+    #[test]
+    fn parse_errors() {
+        let cases = [
+            (String::new(), ParseError::EmptyLine),
+            ("\n".to_string(), ParseError::EmptyLine),
+            (format!("{}  ", HEX), ParseError::ShortLine),
+            (HEX[..10].to_string(), ParseError::ShortLine),
+            (format!("{}é  file", &HEX[..63]), ParseError::NonAsciiPrefix),
+            (format!("{} file", HEX), ParseError::InvalidSpace),
+            (format!("{}\t file", HEX), ParseError::InvalidSpace),
+            (format!("{}g  file", &HEX[..63]), ParseError::InvalidHex),
+            (format!("\\{}  a\\b", HEX), ParseError::InvalidEscape),
+            (format!("\\{}  a\\", HEX), ParseError::InvalidEscape),
+            (format!("{}  a\0b", HEX), ParseError::NullCharacter),
+        ];
+        for (line, expected) in cases.iter() {
+            assert_eq!(ChecksumLine::parse(line), Err(*expected), "{:?}", line);
+            assert_eq!(
+                ChecksumLine::parse_bytes(line.as_bytes()),
+                Err(*expected),
+                "{:?}",
+                line
+            );
+        }
+
+        let mut line = format!("{}  file\n", HEX).into_bytes();
+        assert!(ChecksumLine::parse_bytes(&line).is_ok());
+        line[HEX.len() + 2] = 0xff;
+        assert_eq!(
+            ChecksumLine::parse_bytes(&line),
+            Err(ParseError::InvalidUtf8)
+        );
+    }
+}
@@ -1,7 +1,7 @@
 use crate::platform::{array_ref, array_ref_mut};
-use crate::{counter_high, counter_low, CVBytes, CVWords, BLOCK_LEN, IV, OUT_LEN};
+use crate::{counter_high, counter_low, CVBytes, CVWords, IncrementCounter, BLOCK_LEN, IV, OUT_LEN};
 
-const MSG_SCHEDULE: [[usize; 16]; 7] = [
+pub(crate) const MSG_SCHEDULE: [[usize; 16]; 7] = [
     [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15],
     [2, 6, 3, 10, 7, 0, 4, 13, 1, 11, 12, 5, 9, 14, 15, 8],
     [3, 4, 10, 12, 13, 2, 7, 14, 6, 5, 9, 0, 11, 15, 8, 1],
@@ -100,6 +100,35 @@ pub fn compress_in_place(
     cv[7] = state[7] ^ state[15];
 }
 
+pub fn compress_xof(
+    cv: &CVWords,
+    block: &[u8; BLOCK_LEN],
+    block_len: u8,
+    counter: u64,
+    flags: u8,
+) -> [u8; 64] {
+    let mut state = compress_pre(cv, block, block_len, counter, flags);
+
+    state[0] ^= state[8];
+    state[1] ^= state[9];
+    state[2] ^= state[10];
+    state[3] ^= state[11];
+    state[4] ^= state[12];
+    state[5] ^= state[13];
+    state[6] ^= state[14];
+    state[7] ^= state[15];
+    state[8] ^= cv[0];
+    state[9] ^= cv[1];
+    state[10] ^= cv[2];
+    state[11] ^= cv[3];
+    state[12] ^= cv[4];
+    state[13] ^= cv[5];
+    state[14] ^= cv[6];
+    state[15] ^= cv[7];
+
+    crate::platform::le_bytes_from_words_64(&state)
+}
+
 pub fn hash1<const N: usize>(
     input: &[u8; N],
     key: &CVWords,
@@ -130,17 +159,148 @@ pub fn hash1<const N: usize>(
     *out = crate::platform::le_bytes_from_words_32(&cv);
 }
 
-pub fn hash_many<const N: usize>(
-    inputs: &[&[u8; N]],
+// The number of inputs that hash_many() hashes side by side. The state is kept
+// transposed, as [u32; LANES] vectors holding one word for every input, and
+// each block is compressed by a loop over the lanes whose body is the scalar
+// compression function. LLVM's loop vectorizer turns that loop into SIMD
+// operations on whole vectors. Eight lanes fill a 256-bit AVX2 register, and
+// still beat hash1() with the pairs of 128-bit operations of baseline x86_64.
+pub const LANES: usize = 8;
+
+type Lanes = [u32; LANES];
+
+// Load the block at `block_offset` from each input, transposed so that
+// msg[word][lane] is the given word of the given input's block.
+#[inline(always)]
+fn transpose_msg<const N: usize>(inputs: &[&[u8; N]; LANES], block_offset: usize) -> [Lanes; 16] {
+    let mut msg = [[0; LANES]; 16];
+    for (lane, input) in inputs.iter().enumerate() {
+        let words = crate::platform::words_from_le_bytes_64(array_ref!(input, block_offset, BLOCK_LEN));
+        for (word, msg_word) in words.iter().zip(msg.iter_mut()) {
+            msg_word[lane] = *word;
+        }
+    }
+    msg
+}
+
+// The lane-parallel equivalent of calling hash1() on LANES inputs.
+#[inline(always)]
+#[allow(clippy::too_many_arguments)]
+fn hash_lanes<const N: usize>(
+    inputs: &[&[u8; N]; LANES],
     key: &CVWords,
     counter: u64,
+    increment_counter: IncrementCounter,
     flags: u8,
     flags_start: u8,
     flags_end: u8,
     out: &mut [u8],
+) {
+    debug_assert_eq!(N % BLOCK_LEN, 0, "uneven blocks");
+    let mut counter_low_lanes = [0; LANES];
+    let mut counter_high_lanes = [0; LANES];
+    for lane in 0..LANES {
+        let lane_counter = if increment_counter.yes() {
+            counter + lane as u64
+        } else {
+            counter
+        };
+        counter_low_lanes[lane] = counter_low(lane_counter);
+        counter_high_lanes[lane] = counter_high(lane_counter);
+    }
+
+    let mut cv = [[0; LANES]; 8];
+    for (cv_word, &key_word) in cv.iter_mut().zip(key.iter()) {
+        *cv_word = [key_word; LANES];
+    }
+    let mut block_flags = flags | flags_start;
+    for block_offset in (0..N).step_by(BLOCK_LEN) {
+        if block_offset + BLOCK_LEN == N {
+            block_flags |= flags_end;
+        }
+        let msg = transpose_msg(inputs, block_offset);
+        // Every iteration of this loop runs the same instructions on a
+        // different lane, which is the shape the loop vectorizer needs.
+        for lane in 0..LANES {
+            let mut block_words = [0; 16];
+            for (word, msg_word) in block_words.iter_mut().zip(msg.iter()) {
+                *word = msg_word[lane];
+            }
+            let mut state = [
+                cv[0][lane],
+                cv[1][lane],
+                cv[2][lane],
+                cv[3][lane],
+                cv[4][lane],
+                cv[5][lane],
+                cv[6][lane],
+                cv[7][lane],
+                IV[0],
+                IV[1],
+                IV[2],
+                IV[3],
+                counter_low_lanes[lane],
+                counter_high_lanes[lane],
+                BLOCK_LEN as u32,
+                block_flags as u32,
+            ];
+
+            round(&mut state, &block_words, 0);
+            round(&mut state, &block_words, 1);
+            round(&mut state, &block_words, 2);
+            round(&mut state, &block_words, 3);
+            round(&mut state, &block_words, 4);
+            round(&mut state, &block_words, 5);
+            round(&mut state, &block_words, 6);
+
+            for i in 0..8 {
+                cv[i][lane] = state[i] ^ state[i + 8];
+            }
+        }
+        block_flags = flags;
+    }
+
+    // Transpose the CVs back into one contiguous output per input.
+    for (lane, output) in out.chunks_exact_mut(OUT_LEN).take(LANES).enumerate() {
+        let mut words = [0; 8];
+        for (word, cv_word) in words.iter_mut().zip(cv.iter()) {
+            *word = cv_word[lane];
+        }
+        output.copy_from_slice(&crate::platform::le_bytes_from_words_32(&words));
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn hash_many<const N: usize>(
+    inputs: &[&[u8; N]],
+    key: &CVWords,
+    mut counter: u64,
+    increment_counter: IncrementCounter,
+    flags: u8,
+    flags_start: u8,
+    flags_end: u8,
+    mut out: &mut [u8],
 ) {
     debug_assert!(out.len() >= inputs.len() * OUT_LEN, "out too short");
-    for (&input, output) in inputs.iter().zip(out.chunks_exact_mut(OUT_LEN)) {
+    let mut lane_groups = inputs.chunks_exact(LANES);
+    for group in &mut lane_groups {
+        hash_lanes(
+            array_ref!(group, 0, LANES),
+            key,
+            counter,
+            increment_counter,
+            flags,
+            flags_start,
+            flags_end,
+            out,
+        );
+        if increment_counter.yes() {
+            counter += LANES as u64;
+        }
+        out = &mut out[LANES * OUT_LEN..];
+    }
+
+    for (&input, output) in lane_groups.remainder().iter().zip(out.chunks_exact_mut(OUT_LEN)) {
         hash1(
             input,
             key,
@@ -150,5 +310,8 @@ pub fn hash_many<const N: usize>(
             flags_end,
             array_ref_mut!(output, 0, OUT_LEN),
         );
+        if increment_counter.yes() {
+            counter += 1;
+        }
     }
 }
@@ -0,0 +1,589 @@
+//! Hashing input that arrives out of order, like the ranges of a parallel
+//! download.
+//!
+//! A [`ConcurrentHasher`] is told the length of the input up front, so it
+//! knows the shape of the whole tree before any input arrives. Writes can
+//! come in any order and from any number of threads. Every chunk, and every
+//! aligned subtree that a single write covers, is hashed as soon as its last
+//! byte arrives. The hasher keeps only the chaining values still waiting for
+//! their sibling and the bytes of chunks that have partly arrived, rather than
+//! the whole input.
+//!
+//! This module requires the `std` Cargo feature, which is enabled by default.
+//!
+//! # Example
+//!
+//! ```
+//! use blake3_balanced::concurrent::ConcurrentHasher;
+//! use std::{sync::Arc, thread};
+//!
+//! let input = vec![0xab; 100_000];
+//! let hasher = Arc::new(ConcurrentHasher::new(input.len() as u64));
+//! let mut threads = Vec::new();
+//! for (i, range) in input.chunks(30_000).enumerate().rev() {
+//!     let hasher = Arc::clone(&hasher);
+//!     let range = range.to_vec();
+//!     threads.push(thread::spawn(move || {
+//!         hasher.write_at(i as u64 * 30_000, &range)
+//!     }));
+//! }
+//! for thread in threads {
+//!     thread.join().unwrap()?;
+//! }
+//! assert_eq!(hasher.finalize()?, blake3_balanced::hash(&input));
+//! # Ok::<(), blake3_balanced::concurrent::Error>(())
+//! ```
+
+use crate::guts::{self, Mode};
+use crate::{parent_node_output, Hash, Output, OutputReader, CHUNK_LEN, KEY_LEN};
+use core::fmt;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Mutex, MutexGuard};
+
+/// The error type for [`ConcurrentHasher`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Error {
+    /// A write extends past the length of the input.
+    OutOfBounds,
+    /// A write overlaps bytes that were already written.
+    Overlap,
+    /// Part of the input hasn't been written yet, or a write of it hasn't
+    /// returned yet.
+    Incomplete,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::OutOfBounds => write!(f, "write past the end of the input"),
+            Error::Overlap => write!(f, "write overlaps earlier writes"),
+            Error::Incomplete => write!(f, "input incomplete"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// A hasher for input of a known length that's written in pieces, in any
+/// order, from any number of threads.
+///
+/// Each byte has to be written exactly once with [`write_at`], after which
+/// [`finalize`] gives the same hash as the other hashing functions.
+///
+/// [`write_at`]: ConcurrentHasher::write_at
+/// [`finalize`]: ConcurrentHasher::finalize
+pub struct ConcurrentHasher {
+    mode: Mode,
+    len: u64,
+    state: Mutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+    // The ranges of bytes written so far, from start to end. Adjacent ranges
+    // are merged.
+    written: BTreeMap<u64, u64>,
+    // The chunks that have partly arrived, by chunk index.
+    partial_chunks: HashMap<u64, Box<[u8; CHUNK_LEN]>>,
+    // The complete subtrees waiting for their sibling, by level and index.
+    // The subtree at level `l` and index `i` covers the chunks from `i << l`
+    // up to `(i + 1) << l`, cut off at the end of the input.
+    subtrees: HashMap<(u32, u64), Hash>,
+}
+
+impl State {
+    fn insert_range(&mut self, start: u64, end: u64) -> Result<(), Error> {
+        if let Some((_, &prev_end)) = self.written.range(..end).next_back() {
+            if prev_end > start {
+                return Err(Error::Overlap);
+            }
+        }
+        let mut merged_start = start;
+        if let Some((&prev_start, &prev_end)) = self.written.range(..start).next_back() {
+            if prev_end == start {
+                merged_start = prev_start;
+            }
+        }
+        let merged_end = self.written.remove(&end).unwrap_or(end);
+        self.written.insert(merged_start, merged_end);
+        Ok(())
+    }
+
+    fn is_written(&self, start: u64, end: u64) -> bool {
+        match self.written.range(..=start).next_back() {
+            Some((_, &written_end)) => written_end >= end,
+            None => false,
+        }
+    }
+}
+
+impl ConcurrentHasher {
+    fn new_internal(mode: Mode, len: u64) -> Self {
+        Self {
+            mode,
+            len,
+            state: Mutex::new(State::default()),
+        }
+    }
+
+    /// Construct a new `ConcurrentHasher` for the regular hash function, for
+    /// input of `len` bytes.
+    pub fn new(len: u64) -> Self {
+        Self::new_internal(Mode::hash(), len)
+    }
+
+    /// Construct a new `ConcurrentHasher` for the keyed hash function. See
+    /// [`keyed_hash`](../fn.keyed_hash.html).
+    pub fn new_keyed(key: &[u8; KEY_LEN], len: u64) -> Self {
+        Self::new_internal(Mode::keyed_hash(key), len)
+    }
+
+    /// Construct a new `ConcurrentHasher` for the key derivation function.
+    /// See [`derive_key`](../fn.derive_key.html). The context string should
+    /// be hardcoded, globally unique, and application-specific.
+    pub fn new_derive_key(context: &str, len: u64) -> Self {
+        Self::new_internal(Mode::derive_key(context), len)
+    }
+
+    /// The length of the input, as given to the constructor.
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    /// Return true if the length of the input is zero.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Write `input` at byte `offset` of the input.
+    ///
+    /// The chunks and subtrees that this write completes are hashed before
+    /// it returns, without holding the lock that other writes wait on. A
+    /// write past the end of the input is an [`Error::OutOfBounds`], and a
+    /// write to bytes that were already written is an [`Error::Overlap`].
+    /// Neither changes the state of the hasher.
+    pub fn write_at(&self, offset: u64, input: &[u8]) -> Result<(), Error> {
+        let end = match offset.checked_add(input.len() as u64) {
+            Some(end) if end <= self.len => end,
+            _ => return Err(Error::OutOfBounds),
+        };
+        if input.is_empty() {
+            return Ok(());
+        }
+        let chunk_len = CHUNK_LEN as u64;
+        // The end of the last chunk of an input close to 2^64 bytes long is
+        // one past u64::MAX, so chunk ends saturate below. That comes to the
+        // same thing, since they're all cut off at the end of the input.
+        //
+        // The chunks that this write covers entirely are hashed straight from
+        // `input`. When the whole input is a single chunk, that chunk is the
+        // root, and it's always buffered instead.
+        let mut whole_start = offset / chunk_len + (offset & (chunk_len - 1) != 0) as u64;
+        let mut whole_end = if end == self.len {
+            self.num_chunks()
+        } else {
+            end / chunk_len
+        };
+        if self.num_chunks() == 1 || whole_start >= whole_end {
+            whole_start = offset / chunk_len;
+            whole_end = whole_start;
+        }
+        let whole_bytes_start = offset.max(whole_start * chunk_len);
+        let whole_bytes_end = end
+            .min(whole_end.saturating_mul(chunk_len))
+            .max(whole_bytes_start);
+
+        let mut completed_chunks = Vec::new();
+        {
+            let mut state = self.state();
+            state.insert_range(offset, end)?;
+            let pieces = [(offset, whole_bytes_start), (whole_bytes_end, end)];
+            for &(piece_start, piece_end) in &pieces {
+                let mut position = piece_start;
+                while position < piece_end {
+                    let chunk_index = position / chunk_len;
+                    let chunk_start = chunk_index * chunk_len;
+                    let copy_end = piece_end.min(chunk_start.saturating_add(chunk_len));
+                    let buf = state
+                        .partial_chunks
+                        .entry(chunk_index)
+                        .or_insert_with(|| Box::new([0; CHUNK_LEN]));
+                    buf[(position - chunk_start) as usize..(copy_end - chunk_start) as usize]
+                        .copy_from_slice(
+                            &input[(position - offset) as usize..(copy_end - offset) as usize],
+                        );
+                    let chunk_end = self.len.min(chunk_start.saturating_add(chunk_len));
+                    if self.num_chunks() > 1 && state.is_written(chunk_start, chunk_end) {
+                        let buf = state.partial_chunks.remove(&chunk_index).unwrap();
+                        completed_chunks.push((chunk_index, buf));
+                    }
+                    position = copy_end;
+                }
+            }
+        }
+
+        let mut subtrees = Vec::new();
+        for &(chunk_index, ref buf) in &completed_chunks {
+            let chunk_len = (self.len - chunk_index * chunk_len).min(chunk_len) as usize;
+            subtrees.push((
+                0,
+                chunk_index,
+                self.hash_subtree(&buf[..chunk_len], chunk_index),
+            ));
+        }
+        #[cfg(feature = "zeroize")]
+        for (_, buf) in &mut completed_chunks {
+            zeroize::Zeroize::zeroize(&mut buf[..]);
+        }
+        // Split the whole chunks into the largest aligned subtrees below the
+        // root.
+        let mut chunk_index = whole_start;
+        while chunk_index < whole_end {
+            let level = chunk_index
+                .trailing_zeros()
+                .min(63 - (whole_end - chunk_index).leading_zeros())
+                .min(self.root_level() - 1);
+            let subtree_start = chunk_index * chunk_len;
+            let subtree_end = self
+                .len
+                .min((chunk_index + (1 << level)).saturating_mul(chunk_len));
+            let subtree_input =
+                &input[(subtree_start - offset) as usize..(subtree_end - offset) as usize];
+            subtrees.push((
+                level,
+                chunk_index >> level,
+                self.hash_subtree(subtree_input, chunk_index),
+            ));
+            chunk_index += 1 << level;
+        }
+
+        let mut state = self.state();
+        for (level, index, cv) in subtrees {
+            self.insert_subtree(&mut state, level, index, cv);
+        }
+        Ok(())
+    }
+
+    /// Finalize the hash of the input, or return [`Error::Incomplete`] if
+    /// some of it hasn't been written yet.
+    ///
+    /// This method is idempotent. Calling it twice will give the same result.
+    pub fn finalize(&self) -> Result<Hash, Error> {
+        self.root_output().map(|output| output.root_hash())
+    }
+
+    /// Finalize the hash of the input and return an [`OutputReader`], which
+    /// can supply any number of output bytes. See
+    /// [`Hasher::finalize_xof`](../struct.Hasher.html#method.finalize_xof).
+    pub fn finalize_xof(&self) -> Result<OutputReader, Error> {
+        self.root_output().map(OutputReader::new)
+    }
+
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap()
+    }
+
+    // The empty input is a single empty chunk.
+    fn num_chunks(&self) -> u64 {
+        let chunk_len = CHUNK_LEN as u64;
+        (self.len / chunk_len + (self.len & (chunk_len - 1) != 0) as u64).max(1)
+    }
+
+    fn root_level(&self) -> u32 {
+        self.num_chunks().next_power_of_two().trailing_zeros()
+    }
+
+    fn hash_subtree(&self, input: &[u8], chunk_index: u64) -> Hash {
+        guts::hash_subtree_in_mode(&self.mode, input, chunk_index * CHUNK_LEN as u64)
+            .expect("aligned subtree")
+    }
+
+    // Merge a complete subtree with its sibling if it's there, and keep going
+    // up the tree. The two children of the root are kept as they are, since
+    // the root can only be finalized.
+    fn insert_subtree(&self, state: &mut State, mut level: u32, mut index: u64, mut cv: Hash) {
+        while level + 1 < self.root_level() {
+            let sibling = index ^ 1;
+            if sibling << level >= self.num_chunks() {
+                // A subtree on the right edge of the tree without a sibling
+                // is its own parent.
+            } else if let Some(sibling_cv) = state.subtrees.remove(&(level, sibling)) {
+                cv = if index & 1 == 0 {
+                    guts::parent_cv_in_mode(&self.mode, &cv, &sibling_cv, false)
+                } else {
+                    guts::parent_cv_in_mode(&self.mode, &sibling_cv, &cv, false)
+                };
+            } else {
+                break;
+            }
+            level += 1;
+            index >>= 1;
+        }
+        state.subtrees.insert((level, index), cv);
+    }
+
+    fn root_output(&self) -> Result<Output, Error> {
+        let state = self.state();
+        if self.num_chunks() == 1 {
+            if self.len != 0 && !state.is_written(0, self.len) {
+                return Err(Error::Incomplete);
+            }
+            let mut chunk_state = crate::ChunkState::new(&self.mode.key, 0, self.mode.flags);
+            if let Some(buf) = state.partial_chunks.get(&0) {
+                chunk_state.update(&buf[..self.len as usize]);
+            }
+            return Ok(chunk_state.output());
+        }
+        let level = self.root_level() - 1;
+        match (
+            state.subtrees.get(&(level, 0)),
+            state.subtrees.get(&(level, 1)),
+        ) {
+            (Some(left_child), Some(right_child)) => Ok(parent_node_output(
+                left_child.as_bytes(),
+                right_child.as_bytes(),
+                &self.mode.key,
+                self.mode.flags,
+            )),
+            _ => Err(Error::Incomplete),
+        }
+    }
+}
+
+/// This wipes the key, the bytes of partly written chunks, and the chaining
+/// values of finished subtrees, leaving the `ConcurrentHasher` good for
+/// nothing but dropping. This requires the `zeroize` Cargo feature.
+///
+/// A [`Mode`] is `Copy`, so any copies of the mode made outside the
+/// `ConcurrentHasher` still hold the key, and they aren't wiped.
+#[cfg(feature = "zeroize")]
+impl zeroize::Zeroize for ConcurrentHasher {
+    fn zeroize(&mut self) {
+        self.mode.key.zeroize();
+        self.mode.flags.zeroize();
+        self.len.zeroize();
+        // Wipe the state even if a panicking thread poisoned the lock.
+        let state = match self.state.get_mut() {
+            Ok(state) => state,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        state.written.clear();
+        for buf in state.partial_chunks.values_mut() {
+            buf.zeroize();
+        }
+        state.partial_chunks.clear();
+        for cv in state.subtrees.values_mut() {
+            cv.0.zeroize();
+        }
+        state.subtrees.clear();
+    }
+}
+
+#[cfg(feature = "zeroize")]
+impl Drop for ConcurrentHasher {
+    fn drop(&mut self) {
+        zeroize::Zeroize::zeroize(self);
+    }
+}
+
+#[cfg(feature = "zeroize")]
+impl zeroize::ZeroizeOnDrop for ConcurrentHasher {}
+
+impl fmt::Debug for ConcurrentHasher {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ConcurrentHasher")
+            .field("mode", &self.mode)
+            .field("len", &self.len)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::thread;
+
+    fn test_input(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i % 251) as u8).collect()
+    }
+
+    const TEST_LENS: &[usize] = &[
+        0,
+        1,
+        CHUNK_LEN - 1,
+        CHUNK_LEN,
+        CHUNK_LEN + 1,
+        2 * CHUNK_LEN,
+        3 * CHUNK_LEN + 5,
+        8 * CHUNK_LEN,
+        31 * CHUNK_LEN + 7,
+        100_000,
+    ];
+
+    // Split `0..len` into pieces of `piece_len`, the odd pieces backwards and
+    // then the even ones, so that subtrees are completed from either side.
+    fn pieces(len: usize, piece_len: usize) -> Vec<(usize, usize)> {
+        let starts: Vec<usize> = (0..len).step_by(piece_len).collect();
+        let odd = starts.iter().skip(1).step_by(2).rev();
+        let even = starts.iter().step_by(2);
+        odd.chain(even)
+            .map(|&start| (start, len.min(start + piece_len)))
+            .collect()
+    }
+
+    #[test]
+    fn out_of_order() {
+        for &case in TEST_LENS {
+            let input = test_input(case);
+            let expected = *blake3::hash(&input).as_bytes();
+            for &piece_len in &[1, 100, CHUNK_LEN, 3000, 5 * CHUNK_LEN, case.max(1)] {
+                let pieces = pieces(case, piece_len);
+                assert_eq!(pieces.iter().map(|(s, e)| e - s).sum::<usize>(), case);
+                let hasher = ConcurrentHasher::new(case as u64);
+                for (i, &(start, end)) in pieces.iter().enumerate() {
+                    if i > 0 {
+                        assert_eq!(hasher.finalize(), Err(Error::Incomplete));
+                    }
+                    hasher.write_at(start as u64, &input[start..end]).unwrap();
+                }
+                assert_eq!(
+                    hasher.finalize().unwrap(),
+                    expected,
+                    "{} {}",
+                    case,
+                    piece_len
+                );
+                // Only the two children of the root are left, or the root
+                // chunk when there's just one.
+                let state = hasher.state();
+                if case > CHUNK_LEN {
+                    assert!(state.partial_chunks.is_empty());
+                    assert_eq!(state.subtrees.len(), 2);
+                } else {
+                    assert!(state.subtrees.is_empty());
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn modes() {
+        let input = test_input(7 * CHUNK_LEN + 5);
+        let key = [42; KEY_LEN];
+        let keyed = ConcurrentHasher::new_keyed(&key, input.len() as u64);
+        let derive = ConcurrentHasher::new_derive_key("concurrent", input.len() as u64);
+        for hasher in &[&keyed, &derive] {
+            for &(start, end) in &pieces(input.len(), 1500) {
+                hasher.write_at(start as u64, &input[start..end]).unwrap();
+            }
+        }
+        assert_eq!(
+            keyed.finalize().unwrap(),
+            *blake3::keyed_hash(&key, &input).as_bytes()
+        );
+        assert_eq!(
+            derive.finalize().unwrap(),
+            blake3::derive_key("concurrent", &input)
+        );
+
+        let mut expected = [0; 200];
+        blake3::Hasher::new_keyed(&key)
+            .update(&input)
+            .finalize_xof()
+            .fill(&mut expected);
+        let mut output = [0; 200];
+        keyed.finalize_xof().unwrap().fill(&mut output);
+        assert_eq!(output, expected);
+    }
+
+    #[test]
+    fn threads() {
+        let input = Arc::new(test_input(100 * CHUNK_LEN + 17));
+        let hasher = Arc::new(ConcurrentHasher::new(input.len() as u64));
+        let threads: Vec<_> = pieces(input.len(), 4 * CHUNK_LEN + 17)
+            .into_iter()
+            .map(|(start, end)| {
+                let input = Arc::clone(&input);
+                let hasher = Arc::clone(&hasher);
+                thread::spawn(move || hasher.write_at(start as u64, &input[start..end]))
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap().unwrap();
+        }
+        assert_eq!(hasher.finalize().unwrap(), *blake3::hash(&input).as_bytes());
+    }
+
+    #[test]
+    fn errors() {
+        let input = test_input(4 * CHUNK_LEN);
+        let hasher = ConcurrentHasher::new(input.len() as u64);
+        assert_eq!(hasher.write_at(1, &input), Err(Error::OutOfBounds),);
+        assert_eq!(
+            hasher.write_at(u64::MAX, &input[..2]),
+            Err(Error::OutOfBounds)
+        );
+        hasher.write_at(100, &input[100..2 * CHUNK_LEN]).unwrap();
+        // Overlapping a partial chunk and a hashed chunk.
+        assert_eq!(hasher.write_at(99, &input[99..101]), Err(Error::Overlap));
+        assert_eq!(
+            hasher.write_at(1500, &input[1500..1501]),
+            Err(Error::Overlap)
+        );
+        assert_eq!(
+            hasher.write_at(0, &input[..3 * CHUNK_LEN]),
+            Err(Error::Overlap)
+        );
+        assert_eq!(hasher.finalize(), Err(Error::Incomplete));
+        // Failed writes leave no trace.
+        hasher.write_at(0, &input[..100]).unwrap();
+        hasher
+            .write_at(2 * CHUNK_LEN as u64, &input[2 * CHUNK_LEN..])
+            .unwrap();
+        assert_eq!(hasher.finalize().unwrap(), *blake3::hash(&input).as_bytes());
+        hasher.write_at(input.len() as u64, &[]).unwrap();
+        assert_eq!(hasher.write_at(0, &input[..1]), Err(Error::Overlap));
+
+        // The end of the last chunk doesn't fit in a u64 at the maximum
+        // length.
+        let max = ConcurrentHasher::new(u64::MAX);
+        max.write_at(u64::MAX - 1, &[0]).unwrap();
+        max.write_at(u64::MAX - 3000, &input[..2999]).unwrap();
+        max.write_at(
+            u64::MAX - 2 * CHUNK_LEN as u64 - 3000,
+            &input[..2 * CHUNK_LEN],
+        )
+        .unwrap();
+        assert_eq!(max.write_at(u64::MAX - 1, &[0]), Err(Error::Overlap));
+        assert_eq!(max.write_at(u64::MAX, &[0]), Err(Error::OutOfBounds));
+        assert_eq!(max.finalize(), Err(Error::Incomplete));
+
+        let empty = ConcurrentHasher::new(0);
+        assert!(empty.is_empty());
+        assert_eq!(empty.finalize().unwrap(), *blake3::hash(&[]).as_bytes());
+        assert_eq!(empty.write_at(0, &[0]), Err(Error::OutOfBounds));
+    }
+
+    #[test]
+    #[cfg(feature = "zeroize")]
+    fn zeroize() {
+        use zeroize::Zeroize;
+
+        let input = test_input(5 * CHUNK_LEN);
+        let mut hasher = ConcurrentHasher::new_keyed(&[42; KEY_LEN], input.len() as u64);
+        hasher.write_at(0, &input[..CHUNK_LEN]).unwrap();
+        hasher
+            .write_at(4 * CHUNK_LEN as u64 + 1, &input[..10])
+            .unwrap();
+        hasher.zeroize();
+        assert_eq!(hasher.mode.key, [0; 8]);
+        assert_eq!(hasher.mode.flags, 0);
+        assert_eq!(hasher.len(), 0);
+        let state = hasher.state();
+        assert!(state.written.is_empty());
+        assert!(state.partial_chunks.is_empty());
+        assert!(state.subtrees.is_empty());
+    }
+}
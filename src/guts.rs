@@ -0,0 +1,486 @@
+//! Low-level building blocks of the BLAKE3 tree, for hashing a single input
+//! in pieces, such as across machines.
+//!
+//! A BLAKE3 input is split into 1 KiB chunks, numbered by a chunk counter.
+//! Each chunk is hashed by a [`ChunkState`] into a chaining value, and pairs
+//! of chaining values are merged by [`parent_cv`] into a binary tree. The
+//! left subtree of every parent holds the largest power of two number of
+//! chunks that leaves at least one byte for the right subtree. Only the node
+//! at the very top of the tree is finalized as the root, and its output is
+//! the hash of the whole input. Everything else is a non-root chaining value,
+//! which is not a hash of anything on its own and shouldn't be used as one.
+//!
+//! Getting any of these details wrong gives the wrong hash, without any other
+//! sign of failure. Most callers should use [`Hasher`] instead, which gets
+//! them right, and [`Join`] to parallelize it.
+//!
+//! The keyed hash and key derivation modes use the same tree, with a
+//! different key and flags for every node. Those come from a [`Mode`], which
+//! has to be the same for every node of the tree.
+//!
+//! Rather than hashing every chunk separately, [`hash_subtree`] hashes a
+//! whole range of the input into the chaining value of its subtree, checking
+//! that the range is a subtree at all, and [`merge_subtrees`] combines two of
+//! those. This suits splitting a large input into aligned power-of-two
+//! ranges across workers:
+//!
+//! ```
+//! use blake3_balanced::guts::{hash_subtree, merge_subtrees, CHUNK_LEN};
+//!
+//! // Four chunks and a bit more, split at the largest power of two.
+//! let input = vec![0xab; 4 * CHUNK_LEN + 100];
+//! let (left, right) = input.split_at(4 * CHUNK_LEN);
+//! let left_cv = hash_subtree(left, 0)?;
+//! let right_cv = hash_subtree(right, 4 * CHUNK_LEN as u64)?;
+//! let root = merge_subtrees(&left_cv, &right_cv, true);
+//! assert_eq!(root, blake3_balanced::hash(&input));
+//! # Ok::<(), blake3_balanced::guts::SubtreeError>(())
+//! ```
+//!
+//! # Example
+//!
+//! ```
+//! use blake3_balanced::guts::{parent_cv, ChunkState, CHUNK_LEN};
+//!
+//! // Hash a two-chunk input as two chunks and a root parent node.
+//! let input = [0xab; CHUNK_LEN + 1];
+//! let left = ChunkState::new(0).update(&input[..CHUNK_LEN]).finalize(false);
+//! let right = ChunkState::new(1).update(&input[CHUNK_LEN..]).finalize(false);
+//! let root = parent_cv(&left, &right, true);
+//! assert_eq!(root, blake3_balanced::hash(&input));
+//! ```
+//!
+//! [`Hasher`]: ../struct.Hasher.html
+//! [`Join`]: ../join/trait.Join.html
+
+use crate::{
+    compress_subtree_to_parent_node, hash_all_at_once, join, parent_node_output, platform, CVWords,
+    Hash, Output, BLOCK_LEN, DERIVE_KEY_CONTEXT, DERIVE_KEY_MATERIAL, IV, KEYED_HASH, KEY_LEN,
+    PARENT,
+};
+use core::fmt;
+
+pub use crate::CHUNK_LEN;
+
+/// The key and flags that distinguish the regular hash, keyed hash, and key
+/// derivation modes.
+///
+/// A `Mode` holds the key of the keyed hash and key derivation modes. It's
+/// `Copy`, so it isn't wiped when it's dropped, even with the `zeroize` Cargo
+/// feature.
+#[derive(Clone, Copy)]
+pub struct Mode {
+    pub(crate) key: CVWords,
+    pub(crate) flags: u8,
+}
+
+impl Mode {
+    /// The mode of [`hash`](../fn.hash.html).
+    pub fn hash() -> Self {
+        Self { key: *IV, flags: 0 }
+    }
+
+    /// The mode of [`keyed_hash`](../fn.keyed_hash.html) with the given key.
+    pub fn keyed_hash(key: &[u8; KEY_LEN]) -> Self {
+        Self {
+            key: platform::words_from_le_bytes_32(key),
+            flags: KEYED_HASH,
+        }
+    }
+
+    /// The mode of [`derive_key`](../fn.derive_key.html) with the given
+    /// context string, for hashing the key material. This hashes the context
+    /// string, so it's worth reusing the `Mode` for every node of a tree.
+    pub fn derive_key(context: &str) -> Self {
+        let context_key =
+            hash_all_at_once::<join::SerialJoin>(context.as_bytes(), IV, DERIVE_KEY_CONTEXT)
+                .root_hash();
+        Self {
+            key: platform::words_from_le_bytes_32(context_key.as_bytes()),
+            flags: DERIVE_KEY_MATERIAL,
+        }
+    }
+}
+
+impl Default for Mode {
+    fn default() -> Self {
+        Self::hash()
+    }
+}
+
+// Don't derive(Debug), because the key may be secret.
+impl fmt::Debug for Mode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Mode").field("flags", &self.flags).finish()
+    }
+}
+
+/// An incremental hasher for a single chunk of up to [`CHUNK_LEN`] bytes.
+#[derive(Clone, Debug)]
+pub struct ChunkState(crate::ChunkState);
+
+impl ChunkState {
+    /// Construct a `ChunkState` for the chunk at `chunk_counter` in the regular
+    /// hash mode. The first chunk of an input is chunk 0.
+    pub fn new(chunk_counter: u64) -> Self {
+        Self::new_in_mode(&Mode::hash(), chunk_counter)
+    }
+
+    /// Construct a `ChunkState` for the chunk at `chunk_counter` in the given
+    /// mode.
+    pub fn new_in_mode(mode: &Mode, chunk_counter: u64) -> Self {
+        Self(crate::ChunkState::new(&mode.key, chunk_counter, mode.flags))
+    }
+
+    /// The number of bytes added to this chunk so far.
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    /// Whether any bytes have been added to this chunk yet.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Add input bytes to the chunk. You can call this any number of times.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the chunk would hold more than [`CHUNK_LEN`] bytes.
+    pub fn update(&mut self, input: &[u8]) -> &mut Self {
+        assert!(
+            input.len() <= CHUNK_LEN - self.len(),
+            "chunk longer than CHUNK_LEN"
+        );
+        self.0.update(input);
+        self
+    }
+
+    /// Finalize the chunk into its chaining value, or into the hash of the
+    /// whole input if it's the root. The chunk is the root only when the
+    /// whole input is at most [`CHUNK_LEN`] bytes. This doesn't modify the
+    /// `ChunkState`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `is_root` is true and the chunk counter isn't 0.
+    pub fn finalize(&self, is_root: bool) -> Hash {
+        let output = self.0.output();
+        if is_root {
+            assert_eq!(self.0.chunk_counter, 0, "root chunk with a nonzero counter");
+            output.root_hash()
+        } else {
+            output.chaining_value().into()
+        }
+    }
+}
+
+/// The inner state is also wiped when it's dropped. This requires the
+/// `zeroize` Cargo feature.
+#[cfg(feature = "zeroize")]
+impl zeroize::Zeroize for ChunkState {
+    fn zeroize(&mut self) {
+        self.0.zeroize();
+    }
+}
+
+#[cfg(feature = "zeroize")]
+impl zeroize::ZeroizeOnDrop for ChunkState {}
+
+/// Merge the chaining values of two sibling subtrees into the chaining value
+/// of their parent, or into the hash of the whole input if the parent is the
+/// root, in the regular hash mode.
+pub fn parent_cv(left_child: &Hash, right_child: &Hash, is_root: bool) -> Hash {
+    parent_cv_in_mode(&Mode::hash(), left_child, right_child, is_root)
+}
+
+/// As [`parent_cv`], in the given mode.
+pub fn parent_cv_in_mode(
+    mode: &Mode,
+    left_child: &Hash,
+    right_child: &Hash,
+    is_root: bool,
+) -> Hash {
+    let output = parent_node_output(
+        left_child.as_bytes(),
+        right_child.as_bytes(),
+        &mode.key,
+        mode.flags,
+    );
+    if is_root {
+        output.root_hash()
+    } else {
+        output.chaining_value().into()
+    }
+}
+
+/// The error type for [`hash_subtree`], when the input and offset don't form
+/// a subtree of the BLAKE3 tree.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum SubtreeError {
+    /// The offset isn't a multiple of [`CHUNK_LEN`].
+    MisalignedOffset,
+    /// The input is empty. The only empty subtree is the root of the empty
+    /// input, which is [`hash`](../fn.hash.html) of it.
+    EmptyInput,
+    /// The input is too long for a subtree at its offset. A subtree of `n`
+    /// chunks at a nonzero offset has to start at a multiple of the smallest
+    /// power of two number of chunks that's at least `n`.
+    MisalignedLength,
+}
+
+impl fmt::Display for SubtreeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SubtreeError::MisalignedOffset => write!(f, "offset isn't a multiple of CHUNK_LEN"),
+            SubtreeError::EmptyInput => write!(f, "empty subtree"),
+            SubtreeError::MisalignedLength => write!(f, "subtree too long for its offset"),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for SubtreeError {}
+
+/// Hash `input`, which starts at byte `offset` of the whole input, into the
+/// non-root chaining value of its subtree, in the regular hash mode.
+///
+/// The offset has to be a multiple of [`CHUNK_LEN`], and the input has to
+/// be a whole subtree of the BLAKE3 tree: it can't be empty, and at a
+/// nonzero offset, the number of chunks it covers rounded up to a power of
+/// two has to divide the chunk number of the offset. An input at offset 0
+/// can be any length, though the result is only useful when it's a complete
+/// power of two number of chunks, or the whole input. Use
+/// [`hash`](../fn.hash.html) for the root.
+pub fn hash_subtree(input: &[u8], offset: u64) -> Result<Hash, SubtreeError> {
+    hash_subtree_in_mode(&Mode::hash(), input, offset)
+}
+
+/// As [`hash_subtree`], in the given mode.
+pub fn hash_subtree_in_mode(mode: &Mode, input: &[u8], offset: u64) -> Result<Hash, SubtreeError> {
+    if offset & (CHUNK_LEN as u64 - 1) != 0 {
+        return Err(SubtreeError::MisalignedOffset);
+    }
+    if input.is_empty() {
+        return Err(SubtreeError::EmptyInput);
+    }
+    let chunk_counter = offset / CHUNK_LEN as u64;
+    let num_chunks = ((input.len() - 1) / CHUNK_LEN + 1) as u64;
+    if chunk_counter & (num_chunks.next_power_of_two() - 1) != 0 {
+        return Err(SubtreeError::MisalignedLength);
+    }
+    let output = if input.len() <= CHUNK_LEN {
+        crate::ChunkState::new(&mode.key, chunk_counter, mode.flags)
+            .update(input)
+            .output()
+    } else {
+        Output {
+            input_chaining_value: mode.key,
+            block: compress_subtree_to_parent_node::<join::SerialJoin>(
+                input,
+                &mode.key,
+                chunk_counter,
+                mode.flags,
+            ),
+            block_len: BLOCK_LEN as u8,
+            counter: 0,
+            flags: mode.flags | PARENT,
+        }
+    };
+    Ok(output.chaining_value().into())
+}
+
+/// Combine the chaining values of two adjacent subtrees from
+/// [`hash_subtree`] into the chaining value of their parent, or into the hash
+/// of the whole input if `is_root` is true. The left subtree has to be a
+/// complete power of two number of chunks, at least as many as the right one
+/// has. This is the same as [`parent_cv`], under the name that goes with
+/// `hash_subtree`.
+pub fn merge_subtrees(left: &Hash, right: &Hash, is_root: bool) -> Hash {
+    parent_cv(left, right, is_root)
+}
+
+/// As [`merge_subtrees`], in the given mode.
+pub fn merge_subtrees_in_mode(mode: &Mode, left: &Hash, right: &Hash, is_root: bool) -> Hash {
+    parent_cv_in_mode(mode, left, right, is_root)
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+    use super::*;
+    use crate::left_len;
+    use blake3::hazmat::HasherExt;
+
+    // Hash a subtree of `input` starting at `chunk_counter` with nothing but
+    // the guts API.
+    fn guts_subtree(mode: &Mode, input: &[u8], chunk_counter: u64, is_root: bool) -> Hash {
+        if input.len() <= CHUNK_LEN {
+            return ChunkState::new_in_mode(mode, chunk_counter)
+                .update(input)
+                .finalize(is_root);
+        }
+        let (left, right) = input.split_at(left_len(input.len()));
+        let right_counter = chunk_counter + (left.len() / CHUNK_LEN) as u64;
+        let left_cv = guts_subtree(mode, left, chunk_counter, false);
+        let right_cv = guts_subtree(mode, right, right_counter, false);
+        parent_cv_in_mode(mode, &left_cv, &right_cv, is_root)
+    }
+
+    #[test]
+    fn modes() {
+        let key = [42; KEY_LEN];
+        let context = "blake3-balanced guts test context";
+        for &len in &[
+            0,
+            1,
+            64,
+            65,
+            CHUNK_LEN,
+            CHUNK_LEN + 1,
+            3 * CHUNK_LEN,
+            9 * CHUNK_LEN + 7,
+        ] {
+            let input: std::vec::Vec<u8> = (0..len).map(|i| (i % 251) as u8).collect();
+            let hash = guts_subtree(&Mode::hash(), &input, 0, true);
+            assert_eq!(hash, *blake3::hash(&input).as_bytes(), "len {}", len);
+            let keyed = guts_subtree(&Mode::keyed_hash(&key), &input, 0, true);
+            assert_eq!(keyed, *blake3::keyed_hash(&key, &input).as_bytes());
+            let derived = guts_subtree(&Mode::derive_key(context), &input, 0, true);
+            assert_eq!(derived, blake3::derive_key(context, &input));
+        }
+    }
+
+    #[test]
+    fn non_root() {
+        let input = [7; 4 * CHUNK_LEN];
+        let key = [42; KEY_LEN];
+        let context = "blake3-balanced guts test context";
+        let modes = [
+            (Mode::hash(), blake3::Hasher::new()),
+            (Mode::keyed_hash(&key), blake3::Hasher::new_keyed(&key)),
+            (
+                Mode::derive_key(context),
+                blake3::Hasher::new_derive_key(context),
+            ),
+        ];
+        for (mode, hasher) in modes.iter() {
+            // A chunk and a subtree that aren't at the start of the input.
+            for &(offset, len) in &[(CHUNK_LEN, CHUNK_LEN), (2 * CHUNK_LEN, 2 * CHUNK_LEN)] {
+                let chunk_counter = (offset / CHUNK_LEN) as u64;
+                let cv = guts_subtree(mode, &input[offset..][..len], chunk_counter, false);
+                let expected = hasher
+                    .clone()
+                    .set_input_offset(offset as u64)
+                    .update(&input[offset..][..len])
+                    .finalize_non_root();
+                assert_eq!(cv, expected, "{:?} offset {}", mode, offset);
+            }
+        }
+    }
+
+    #[test]
+    fn update_in_pieces() {
+        let input = [9; CHUNK_LEN];
+        let mut state = ChunkState::new(5);
+        assert!(state.is_empty());
+        for piece in input.chunks(100) {
+            state.update(piece);
+        }
+        assert_eq!(state.len(), CHUNK_LEN);
+        let whole = ChunkState::new(5).update(&input).finalize(false);
+        assert_eq!(state.finalize(false), whole);
+    }
+
+    #[test]
+    #[should_panic]
+    fn update_too_long() {
+        ChunkState::new(0).update(&[0; CHUNK_LEN]).update(&[0]);
+    }
+
+    #[test]
+    #[should_panic]
+    fn root_with_counter() {
+        ChunkState::new(1).finalize(true);
+    }
+
+    #[test]
+    fn subtrees() {
+        let input: std::vec::Vec<u8> = (0..16 * CHUNK_LEN).map(|i| (i % 251) as u8).collect();
+        let key = [42; KEY_LEN];
+        for mode in &[
+            Mode::hash(),
+            Mode::keyed_hash(&key),
+            Mode::derive_key("subtrees"),
+        ] {
+            for &(offset, len) in &[
+                (0, 1),
+                (0, 3 * CHUNK_LEN + 1),
+                (CHUNK_LEN, CHUNK_LEN),
+                (2 * CHUNK_LEN, 2 * CHUNK_LEN),
+                (4 * CHUNK_LEN, 3 * CHUNK_LEN),
+                (8 * CHUNK_LEN, 5 * CHUNK_LEN + 9),
+            ] {
+                let range = &input[offset..][..len];
+                let cv = hash_subtree_in_mode(mode, range, offset as u64).unwrap();
+                let chunk_counter = (offset / CHUNK_LEN) as u64;
+                assert_eq!(cv, guts_subtree(mode, range, chunk_counter, false));
+            }
+
+            // Four workers hash a quarter each, and the results merge into
+            // the hash of the whole.
+            let quarter = 4 * CHUNK_LEN;
+            let cvs: std::vec::Vec<Hash> = (0..4)
+                .map(|i| {
+                    hash_subtree_in_mode(
+                        mode,
+                        &input[i * quarter..][..quarter],
+                        (i * quarter) as u64,
+                    )
+                })
+                .collect::<Result<_, _>>()
+                .unwrap();
+            let left = merge_subtrees_in_mode(mode, &cvs[0], &cvs[1], false);
+            let right = merge_subtrees_in_mode(mode, &cvs[2], &cvs[3], false);
+            let root = merge_subtrees_in_mode(mode, &left, &right, true);
+            assert_eq!(root, guts_subtree(mode, &input, 0, true));
+        }
+        assert_eq!(
+            merge_subtrees(
+                &hash_subtree(&input[..CHUNK_LEN], 0).unwrap(),
+                &hash_subtree(&input[CHUNK_LEN..2 * CHUNK_LEN], CHUNK_LEN as u64).unwrap(),
+                true
+            ),
+            *blake3::hash(&input[..2 * CHUNK_LEN]).as_bytes()
+        );
+    }
+
+    #[test]
+    fn subtree_errors() {
+        let input = [0; 4 * CHUNK_LEN];
+        assert_eq!(hash_subtree(&input, 1), Err(SubtreeError::MisalignedOffset));
+        assert_eq!(
+            hash_subtree(&input, CHUNK_LEN as u64 + 64),
+            Err(SubtreeError::MisalignedOffset)
+        );
+        assert_eq!(hash_subtree(&[], 0), Err(SubtreeError::EmptyInput));
+        assert_eq!(
+            hash_subtree(&[], CHUNK_LEN as u64),
+            Err(SubtreeError::EmptyInput)
+        );
+        // Two chunks can't start at an odd chunk, and three chunks need
+        // a multiple of four.
+        let offset = CHUNK_LEN as u64;
+        assert_eq!(
+            hash_subtree(&input[..CHUNK_LEN + 1], offset),
+            Err(SubtreeError::MisalignedLength)
+        );
+        assert_eq!(
+            hash_subtree(&input[..3 * CHUNK_LEN], 2 * offset),
+            Err(SubtreeError::MisalignedLength)
+        );
+        assert!(hash_subtree(&input[..3 * CHUNK_LEN], 4 * offset).is_ok());
+        assert!(hash_subtree(&input, 0).is_ok());
+    }
+}
@@ -0,0 +1,74 @@
+//! Helper functions for efficient IO.
+
+use crate::{Hasher, CHUNK_LEN};
+
+// The buffer is a whole number of chunks, so that every full read can be
+// hashed as complete subtrees by Hasher::update_with_join(). 64 KiB is also
+// large enough to amortize the cost of each read call.
+const BUF_LEN: usize = 64 * CHUNK_LEN;
+
+// This is like std::io::copy, but it uses a buffer that's a multiple of
+// CHUNK_LEN, rather than the 8 KiB default. It also passes the buffer to
+// Hasher::update directly, rather than going through the Write impl.
+pub(crate) fn copy_wide(mut reader: impl std::io::Read, hasher: &mut Hasher) -> std::io::Result<u64> {
+    let mut buffer = [0; BUF_LEN];
+    let mut total = 0;
+    loop {
+        match reader.read(&mut buffer) {
+            Ok(0) => return Ok(total),
+            Ok(n) => {
+                hasher.update(&buffer[..n]);
+                total += n as u64;
+            }
+            // See the read_to_end() implementation in std.
+            Err(ref e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+// Files shorter than this are cheaper to read than to map.
+#[cfg(feature = "mmap")]
+const MIN_MMAP_LEN: u64 = 16 * CHUNK_LEN as u64;
+
+// Mmap a file, if it looks like a good idea. Return None in cases where we
+// know mmap will fail, or if the file is short enough that mmapping isn't
+// worth it. However, if we do try to mmap and it fails, return the error.
+//
+// SAFETY: Mmaps are fundamentally unsafe, because you can call
+// invariant-checking functions like str::from_utf8 on them and then have them
+// change out from under you. Letting a safe caller get their hands on an mmap,
+// or even a &[u8] that's backed by an mmap, is unsound. However, because this
+// function is crate-private, we can guarantee that all that can ever happen in
+// the event of a race condition is that we either hash nonsense bytes or crash
+// with SIGBUS or similar, neither of which should risk memory corruption in a
+// safe caller.
+#[cfg(feature = "mmap")]
+#[allow(unsafe_code)]
+pub(crate) fn maybe_mmap_file(file: &std::fs::File) -> std::io::Result<Option<memmap2::Mmap>> {
+    let metadata = file.metadata()?;
+    let file_size = metadata.len();
+    if !metadata.is_file() {
+        // Not a real file. Pipes, sockets, and character devices can't be
+        // mapped, and their reported size is meaningless.
+        Ok(None)
+    } else if file_size > isize::MAX as u64 {
+        // Too long to safely map.
+        // https://doc.rust-lang.org/std/slice/fn.from_raw_parts.html
+        Ok(None)
+    } else if file_size < MIN_MMAP_LEN {
+        // Mapping an empty file currently fails, and short files aren't worth
+        // the overhead.
+        // https://github.com/danburkert/memmap-rs/issues/72
+        Ok(None)
+    } else {
+        // Explicitly set the length of the memory map, so that filesystem
+        // changes can't race to violate the invariants we just checked.
+        let map = unsafe {
+            memmap2::MmapOptions::new()
+                .len(file_size as usize)
+                .map(file)?
+        };
+        Ok(Some(map))
+    }
+}
@@ -1,7 +1,77 @@
+//! The multithreading abstractions used by [`Hasher::update_with_join`] and
+//! [`hash_with_join`].
+//!
+//! Hashing a large input splits it into a tree of subtrees, and the left and
+//! right halves of each subtree can be hashed independently. The [`Join`]
+//! trait decides how those halves run. [`SerialJoin`] runs them one after the
+//! other on the calling thread, and is what [`hash`] and [`Hasher::update`]
+//! use. [`RayonJoin`], gated by the `rayon` feature, runs them on the Rayon
+//! thread pool, and is what `Hasher::update_rayon` uses. [`ThreadJoin`],
+//! gated by the `threads` feature, backs `Hasher::update_threads`.
+//!
+//! Callers can implement `Join` themselves, to drive hashing from any
+//! fork-join executor, such as a custom thread pool or a job system. The
+//! trait is an almost exact copy of the [`rayon::join`] API.
+//!
+//! # Example
+//!
+//! ```
+//! use blake3_balanced::join::Join;
+//! use std::sync::atomic::{AtomicUsize, Ordering};
+//!
+//! static JOINS: AtomicUsize = AtomicUsize::new(0);
+//!
+//! // A serial Join that counts how many times it's called. A real
+//! // implementation would hand `oper_b` to another worker, run `oper_a`
+//! // itself, and then wait for `oper_b` to finish.
+//! enum CountingJoin {}
+//!
+//! impl Join for CountingJoin {
+//!     fn join<A, B, RA, RB>(oper_a: A, oper_b: B) -> (RA, RB)
+//!     where
+//!         A: FnOnce() -> RA + Send,
+//!         B: FnOnce() -> RB + Send,
+//!         RA: Send,
+//!         RB: Send,
+//!     {
+//!         JOINS.fetch_add(1, Ordering::Relaxed);
+//!         (oper_a(), oper_b())
+//!     }
+//! }
+//!
+//! let input = vec![0xab; 1 << 20];
+//! let hash = blake3_balanced::hash_with_join::<CountingJoin>(&input);
+//! assert_eq!(hash, blake3_balanced::hash(&input));
+//! assert!(JOINS.load(Ordering::Relaxed) > 0);
+//!
+//! let mut hasher = blake3_balanced::Hasher::new();
+//! hasher.update_with_join::<CountingJoin>(&input);
+//! assert_eq!(hasher.finalize(), hash);
+//! ```
+//!
+//! [`Hasher::update_with_join`]: ../struct.Hasher.html#method.update_with_join
+//! [`Hasher::update`]: ../struct.Hasher.html#method.update
+//! [`hash_with_join`]: ../fn.hash_with_join.html
+//! [`hash`]: ../fn.hash.html
+//! [`RayonJoin`]: enum.RayonJoin.html
+//! [`ThreadJoin`]: enum.ThreadJoin.html
+//! [`rayon::join`]: https://docs.rs/rayon/1/rayon/fn.join.html
+
 /// The trait that abstracts over single-threaded and multi-threaded recursion.
 ///
+/// An implementation must call each of `oper_a` and `oper_b` exactly once,
+/// and return their results in that order. It may run them one after the
+/// other or at the same time, on any threads. If either of them panics, the
+/// panic should propagate to the caller of `join`.
+///
 /// See the [`join` module docs](index.html) for more details.
 pub trait Join {
+    /// The shortest subtree, in bytes, worth splitting with `join`. Subtrees
+    /// shorter than this are hashed serially on the current thread, without
+    /// calling `join` at all. The default of 0 calls `join` at every level of
+    /// the tree.
+    const MIN_LEN: usize = 0;
+
     fn join<A, B, RA, RB>(oper_a: A, oper_b: B) -> (RA, RB)
     where
         A: FnOnce() -> RA + Send,
@@ -35,12 +105,27 @@ impl Join for SerialJoin {
 /// executed on the Rayon thread pool, potentially in parallel. This
 /// implementation is gated by the `rayon` feature, which is off by default.
 ///
+/// Subtrees shorter than `MIN_LEN` bytes are hashed serially, because the
+/// cost of a Rayon task outweighs the parallelism for them. `RayonJoin` on
+/// its own uses [`RAYON_DEFAULT_MIN_LEN`], and callers can pick another
+/// threshold with, for example, `RayonJoin<{ 64 * 1024 }>`.
+///
 /// See the [`join` module docs](index.html) for more details.
 #[cfg(feature = "rayon")]
-pub enum RayonJoin {}
+pub enum RayonJoin<const MIN_LEN: usize = RAYON_DEFAULT_MIN_LEN> {}
 
+/// The default `MIN_LEN` of [`RayonJoin`], and the threshold that
+/// `Hasher::update_rayon` uses. Measured with the `rayon-min-len` group of
+/// the `element-web-vendor` benchmark, subtrees this large take over a
+/// hundred microseconds to hash, and splitting them further costs more in
+/// Rayon tasks than it gains.
 #[cfg(feature = "rayon")]
-impl Join for RayonJoin {
+pub const RAYON_DEFAULT_MIN_LEN: usize = 128 * 1024;
+
+#[cfg(feature = "rayon")]
+impl<const MIN_LEN: usize> Join for RayonJoin<MIN_LEN> {
+    const MIN_LEN: usize = MIN_LEN;
+
     #[inline]
     fn join<A, B, RA, RB>(oper_a: A, oper_b: B) -> (RA, RB)
     where
@@ -52,3 +137,78 @@ impl Join for RayonJoin {
         rayon::join(oper_a, oper_b)
     }
 }
+
+/// The implementation of `Join` based on scoped standard threads, for
+/// multithreading without a Rayon dependency. Each call to `join` splits the
+/// current thread budget in half, running the right side on a new thread with
+/// one half and the left side on the calling thread with the other, until the
+/// budget runs out and the rest of the recursion is serial.
+/// `Hasher::update_threads` sets the budget, which is otherwise 1, so using
+/// `ThreadJoin` anywhere else is equivalent to `SerialJoin`. This
+/// implementation is gated by the `threads` feature, which is off by default.
+///
+/// See the [`join` module docs](index.html) for more details.
+#[cfg(feature = "threads")]
+pub enum ThreadJoin {}
+
+// Inputs shorter than this per thread aren't worth the cost of spawning one.
+// Hashing this much takes a few hundred microseconds with the portable
+// implementation, compared to tens of microseconds to spawn a thread.
+#[cfg(feature = "threads")]
+pub(crate) const MIN_THREAD_LEN: usize = 128 * 1024;
+
+#[cfg(feature = "threads")]
+std::thread_local! {
+    // The number of threads, including the current one, that ThreadJoin may
+    // use for the work it's currently doing on this thread.
+    static THREAD_BUDGET: core::cell::Cell<usize> = const { core::cell::Cell::new(1) };
+}
+
+#[cfg(feature = "threads")]
+impl ThreadJoin {
+    // Run `oper` with the given thread budget on the current thread, and
+    // restore the previous budget afterwards, even if `oper` panics.
+    pub(crate) fn with_budget<R>(budget: usize, oper: impl FnOnce() -> R) -> R {
+        struct RestoreBudget(usize);
+
+        impl Drop for RestoreBudget {
+            fn drop(&mut self) {
+                THREAD_BUDGET.with(|budget| budget.set(self.0));
+            }
+        }
+
+        let _restore = RestoreBudget(THREAD_BUDGET.with(|cell| cell.replace(budget)));
+        oper()
+    }
+}
+
+#[cfg(feature = "threads")]
+impl Join for ThreadJoin {
+    const MIN_LEN: usize = MIN_THREAD_LEN;
+
+    #[inline]
+    fn join<A, B, RA, RB>(oper_a: A, oper_b: B) -> (RA, RB)
+    where
+        A: FnOnce() -> RA + Send,
+        B: FnOnce() -> RB + Send,
+        RA: Send,
+        RB: Send,
+    {
+        let budget = THREAD_BUDGET.with(|budget| budget.get());
+        if budget < 2 {
+            return (oper_a(), oper_b());
+        }
+        let budget_b = budget / 2;
+        let budget_a = budget - budget_b;
+        std::thread::scope(|scope| {
+            let handle = scope.spawn(move || Self::with_budget(budget_b, oper_b));
+            let result_a = Self::with_budget(budget_a, oper_a);
+            match handle.join() {
+                Ok(result_b) => (result_a, result_b),
+                // Propagate a panic from the right side with its original
+                // payload.
+                Err(payload) => std::panic::resume_unwind(payload),
+            }
+        })
+    }
+}
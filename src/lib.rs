@@ -6,27 +6,53 @@
 //!
 //! # Example
 //!
-//! ```no_run
+//! ```
 //! let mut hasher = blake3_balanced::Hasher::new();
 //! hasher.update(b"abc");
 //! hasher.update(b"def");
-//! //let mut hash = [0; 32];
-//! //hasher.finalize(&mut hash);
-//! //let mut extended_hash = [0; 500];
-//! //hasher.finalize(&mut extended_hash);
-//! //assert_eq!(hash, extended_hash[..32]);
+//! let hash = hasher.finalize();
+//! let mut extended_hash = [0; 500];
+//! hasher.finalize_xof().fill(&mut extended_hash);
+//! assert_eq!(hash.as_bytes(), &extended_hash[..32]);
 //! ```
 //!
 
-//#![cfg_attr(not(feature = "std"), no_std)]
-#![forbid(unsafe_code)]
+#![cfg_attr(not(feature = "std"), no_std)]
+// Only the optional memory-mapping support and the x86_64 intrinsics need
+// unsafe code, and they opt in locally with #[allow(unsafe_code)].
+#![cfg_attr(not(any(feature = "mmap", feature = "intrinsics")), forbid(unsafe_code))]
+#![cfg_attr(any(feature = "mmap", feature = "intrinsics"), deny(unsafe_code))]
 
-use crate::platform::{array_ref, array_ref_mut};
+use crate::platform::{array_ref, array_ref_mut, Platform};
+use arrayvec::{ArrayString, ArrayVec};
+use core::convert::TryFrom;
 use core::{cmp, fmt};
 
+#[cfg(feature = "rayon")]
+pub use join::RayonJoin;
+#[cfg(feature = "threads")]
+pub use join::ThreadJoin;
+pub use join::{Join, SerialJoin};
+
+#[cfg(all(feature = "intrinsics", target_arch = "x86_64"))]
+mod avx2;
+#[cfg(feature = "bao")]
+pub mod bao;
+#[cfg(feature = "std")]
+pub mod checksum;
 mod compress;
-mod join;
+#[cfg(feature = "std")]
+pub mod concurrent;
+pub mod guts;
+#[cfg(feature = "std")]
+mod io;
+pub mod join;
 mod platform;
+#[cfg(all(feature = "intrinsics", target_arch = "x86_64"))]
+mod sse41;
+mod state;
+#[cfg(feature = "traits-preview")]
+pub mod traits;
 
 // While iterating the compression function within a chunk, the CV is
 // represented as words, to avoid doing two extra endianness conversions for
@@ -63,6 +89,10 @@ pub const KEY_LEN: usize = 32;
 
 const MAX_DEPTH: usize = 54; // 2^54 * CHUNK_LEN = 2^64
 
+/// The maximum number of bytes in
+/// [`Hasher::to_state_bytes`](struct.Hasher.html#method.to_state_bytes).
+pub const MAX_STATE_LEN: usize = state::HEADER_LEN + 32 * (MAX_DEPTH + 1);
+
 #[inline]
 fn counter_low(counter: u64) -> u32 {
     counter as u32
@@ -73,6 +103,34 @@ fn counter_high(counter: u64) -> u32 {
     (counter >> 32) as u32
 }
 
+/// An output of the default size, 32 bytes, which provides constant-time
+/// equality checking.
+///
+/// `Hash` implements [`From`] and [`Into`] for `[u8; 32]`, and it provides
+/// [`from_bytes`] and [`as_bytes`] for explicit conversions between itself
+/// and `[u8; 32]`. However, byte arrays and slices don't provide
+/// constant-time equality checking, which is often a security requirement in
+/// software that handles private data. `Hash` doesn't implement [`Deref`] or
+/// [`AsRef`], to avoid situations where a type conversion happens
+/// implicitly and the constant-time property is accidentally lost.
+///
+/// `Hash` provides the [`to_hex`] and [`from_hex`] methods for converting to
+/// and from hexadecimal. It also implements [`Display`] and [`FromStr`].
+///
+/// [`From`]: https://doc.rust-lang.org/std/convert/trait.From.html
+/// [`Into`]: https://doc.rust-lang.org/std/convert/trait.Into.html
+/// [`from_bytes`]: #method.from_bytes
+/// [`as_bytes`]: #method.as_bytes
+/// [`Deref`]: https://doc.rust-lang.org/stable/std/ops/trait.Deref.html
+/// [`AsRef`]: https://doc.rust-lang.org/std/convert/trait.AsRef.html
+/// [`to_hex`]: #method.to_hex
+/// [`from_hex`]: #method.from_hex
+/// [`Display`]: https://doc.rust-lang.org/std/fmt/trait.Display.html
+/// [`FromStr`]: https://doc.rust-lang.org/std/str/trait.FromStr.html
+// The manual PartialEq impls compare the same bytes that the derived Hash
+// hashes, just in constant time, so the two stay consistent.
+#[allow(clippy::derived_hash_with_manual_eq)]
+#[derive(Clone, Copy, Hash)]
 pub struct Hash([u8; OUT_LEN]);
 
 impl Hash {
@@ -83,8 +141,282 @@ impl Hash {
     pub fn as_bytes(&self) -> &[u8; OUT_LEN] {
         &self.0
     }
+
+    /// Create a `Hash` from its raw bytes representation.
+    pub const fn from_bytes(bytes: [u8; OUT_LEN]) -> Self {
+        Self(bytes)
+    }
+
+    /// Create a `Hash` from its raw bytes representation as a slice.
+    ///
+    /// Returns an error if the slice is not exactly 32 bytes long.
+    pub fn from_slice(bytes: &[u8]) -> Result<Self, core::array::TryFromSliceError> {
+        Ok(Self::from_bytes(<[u8; OUT_LEN]>::try_from(bytes)?))
+    }
+
+    /// Encode a `Hash` in lowercase hexadecimal.
+    ///
+    /// The returned [`ArrayString`] is a fixed size and doesn't allocate
+    /// memory on the heap. Note that [`ArrayString`] doesn't provide
+    /// constant-time equality checking, so if you need to compare hashes,
+    /// prefer the `Hash` type.
+    ///
+    /// [`ArrayString`]: https://docs.rs/arrayvec/0.7/arrayvec/struct.ArrayString.html
+    pub fn to_hex(&self) -> ArrayString<{ 2 * OUT_LEN }> {
+        let mut s = ArrayString::new();
+        let table = b"0123456789abcdef";
+        for &b in self.0.iter() {
+            s.push(table[(b >> 4) as usize] as char);
+            s.push(table[(b & 0xf) as usize] as char);
+        }
+        s
+    }
+
+    /// Decode a `Hash` from hexadecimal. Both uppercase and lowercase ASCII
+    /// bytes are supported.
+    ///
+    /// Any byte outside the ranges `'0'...'9'`, `'a'...'f'`, and `'A'...'F'`
+    /// results in an error. An input length other than 64 also results in an
+    /// error.
+    ///
+    /// Note that `Hash` also implements `FromStr`, so `Hash::from_hex("...")`
+    /// is equivalent to `"...".parse()`.
+    pub fn from_hex(hex: impl AsRef<[u8]>) -> Result<Self, HexError> {
+        fn hex_val(byte: u8) -> Result<u8, HexError> {
+            match byte {
+                b'A'..=b'F' => Ok(byte - b'A' + 10),
+                b'a'..=b'f' => Ok(byte - b'a' + 10),
+                b'0'..=b'9' => Ok(byte - b'0'),
+                _ => Err(HexError(HexErrorInner::InvalidByte(byte))),
+            }
+        }
+        let hex_bytes: &[u8] = hex.as_ref();
+        if hex_bytes.len() != OUT_LEN * 2 {
+            return Err(HexError(HexErrorInner::InvalidLen(hex_bytes.len())));
+        }
+        let mut hash_bytes: [u8; OUT_LEN] = [0; OUT_LEN];
+        for (byte, pair) in hash_bytes.iter_mut().zip(hex_bytes.chunks_exact(2)) {
+            *byte = 16 * hex_val(pair[0])? + hex_val(pair[1])?;
+        }
+        Ok(Hash::from(hash_bytes))
+    }
+}
+
+impl From<[u8; OUT_LEN]> for Hash {
+    #[inline]
+    fn from(bytes: [u8; OUT_LEN]) -> Self {
+        Self::from_bytes(bytes)
+    }
+}
+
+impl From<Hash> for [u8; OUT_LEN] {
+    #[inline]
+    fn from(hash: Hash) -> Self {
+        hash.0
+    }
+}
+
+/// This implementation is constant-time.
+impl PartialEq for Hash {
+    #[inline]
+    fn eq(&self, other: &Hash) -> bool {
+        platform::constant_time_eq(&self.0, &other.0)
+    }
+}
+
+/// This implementation is constant-time.
+impl PartialEq<[u8; OUT_LEN]> for Hash {
+    #[inline]
+    fn eq(&self, other: &[u8; OUT_LEN]) -> bool {
+        platform::constant_time_eq(&self.0, other)
+    }
+}
+
+/// This implementation is constant-time if the target is 32 bytes long.
+impl PartialEq<[u8]> for Hash {
+    #[inline]
+    fn eq(&self, other: &[u8]) -> bool {
+        platform::constant_time_eq(&self.0, other)
+    }
+}
+
+impl Eq for Hash {}
+
+impl core::str::FromStr for Hash {
+    type Err = HexError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Hash::from_hex(s)
+    }
+}
+
+impl fmt::Display for Hash {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let hex = self.to_hex();
+        let hex: &str = hex.as_str();
+
+        f.write_str(hex)
+    }
+}
+
+impl fmt::Debug for Hash {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // Formatting field as `&str` to reduce code size since the `Debug`
+        // dynamic dispatch table for `&str` is likely needed elsewhere already,
+        // but that for `ArrayString<[u8; 64]>` is not.
+        let hex = self.to_hex();
+        let hex: &str = hex.as_str();
+
+        f.debug_tuple("Hash").field(&hex).finish()
+    }
+}
+
+/// The error type for [`Hash::from_hex`].
+///
+/// The `.to_string()` representation of this error currently distinguishes
+/// between bad length errors and bad character errors. This is to help with
+/// logging and debugging, but it isn't a stable API detail, and it may
+/// change at any time.
+///
+/// [`Hash::from_hex`]: struct.Hash.html#method.from_hex
+#[derive(Clone, Debug)]
+pub struct HexError(HexErrorInner);
+
+#[derive(Clone, Debug)]
+enum HexErrorInner {
+    InvalidByte(u8),
+    InvalidLen(usize),
+}
+
+impl fmt::Display for HexError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.0 {
+            HexErrorInner::InvalidByte(byte) => {
+                if byte < 128 {
+                    write!(f, "invalid hex character: {:?}", byte as char)
+                } else {
+                    write!(f, "invalid hex character: 0x{:x}", byte)
+                }
+            }
+            HexErrorInner::InvalidLen(len) => {
+                write!(f, "expected 64 hex bytes, received {}", len)
+            }
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for HexError {}
+
+/// The error type for [`verify_mac`](fn.verify_mac.html).
+///
+/// This deliberately doesn't say whether the tag had the wrong length or the
+/// wrong contents.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MacError(());
+
+impl fmt::Display for MacError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("MAC verification failed")
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for MacError {}
+
+/// The error type for
+/// [`Hasher::from_state_bytes`](struct.Hasher.html#method.from_state_bytes).
+///
+/// Like [`HexError`], the `.to_string()` representation says which part of
+/// the state was invalid, but that isn't a stable API detail.
+#[derive(Clone, Debug)]
+pub struct StateError(StateErrorInner);
+
+#[derive(Clone, Debug)]
+enum StateErrorInner {
+    InvalidLen(usize),
+    UnknownVersion(u8),
+    InvalidMode,
+    InvalidChunk,
+    InvalidStack,
+}
+
+impl fmt::Display for StateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.0 {
+            StateErrorInner::InvalidLen(len) => write!(f, "invalid state length: {}", len),
+            StateErrorInner::UnknownVersion(version) => {
+                write!(f, "unknown state version: {}", version)
+            }
+            StateErrorInner::InvalidMode => f.write_str("invalid hash mode in state"),
+            StateErrorInner::InvalidChunk => f.write_str("inconsistent chunk state"),
+            StateErrorInner::InvalidStack => f.write_str("inconsistent CV stack"),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for StateError {}
+
+/// A key for [`keyed_hash`], [`Hasher::new_keyed`], or [`verify_mac`] that's
+/// wiped from memory when it's dropped.
+///
+/// This requires the `zeroize` Cargo feature. The [`Hasher`] wipes its own
+/// copy of the key too.
+///
+/// ```
+/// use blake3_balanced::Key;
+///
+/// let key = Key::from([42; 32]);
+/// let tag = blake3_balanced::keyed_hash(key.as_bytes(), b"message");
+/// assert!(blake3_balanced::verify_mac(key.as_bytes(), b"message", tag.as_bytes()).is_ok());
+/// ```
+#[cfg(feature = "zeroize")]
+#[derive(Clone)]
+pub struct Key([u8; KEY_LEN]);
+
+#[cfg(feature = "zeroize")]
+impl Key {
+    /// The bytes of the key.
+    pub fn as_bytes(&self) -> &[u8; KEY_LEN] {
+        &self.0
+    }
+}
+
+/// The array passed in is a copy, which the caller should wipe separately if
+/// it's still around.
+#[cfg(feature = "zeroize")]
+impl From<[u8; KEY_LEN]> for Key {
+    fn from(bytes: [u8; KEY_LEN]) -> Self {
+        Self(bytes)
+    }
+}
+
+// Don't derive(Debug), because the key is secret.
+#[cfg(feature = "zeroize")]
+impl fmt::Debug for Key {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("Key(..)")
+    }
+}
+
+#[cfg(feature = "zeroize")]
+impl zeroize::Zeroize for Key {
+    fn zeroize(&mut self) {
+        self.0.zeroize();
+    }
+}
+
+#[cfg(feature = "zeroize")]
+impl Drop for Key {
+    fn drop(&mut self) {
+        zeroize::Zeroize::zeroize(self);
+    }
 }
 
+#[cfg(feature = "zeroize")]
+impl zeroize::ZeroizeOnDrop for Key {}
+
 // Each chunk or parent node can produce either a 32-byte chaining value or, by
 // setting the ROOT flag, any number of final output bytes. The Output struct
 // captures the state just prior to choosing between those two possibilities.
@@ -100,7 +432,7 @@ struct Output {
 impl Output {
     fn chaining_value(&self) -> CVBytes {
         let mut cv = self.input_chaining_value;
-        compress::compress_in_place(
+        Platform::detect().compress_in_place(
             &mut cv,
             &self.block,
             self.block_len,
@@ -113,11 +445,48 @@ impl Output {
     fn root_hash(&self) -> Hash {
         debug_assert_eq!(self.counter, 0);
         let mut cv = self.input_chaining_value;
-        compress::compress_in_place(&mut cv, &self.block, self.block_len, 0, self.flags | ROOT);
+        Platform::detect().compress_in_place(
+            &mut cv,
+            &self.block,
+            self.block_len,
+            0,
+            self.flags | ROOT,
+        );
         Hash(platform::le_bytes_from_words_32(&cv))
     }
+
+    fn root_output_block(&self) -> [u8; 2 * OUT_LEN] {
+        Platform::detect().compress_xof(
+            &self.input_chaining_value,
+            &self.block,
+            self.block_len,
+            self.counter,
+            self.flags | ROOT,
+        )
+    }
+}
+
+#[cfg(feature = "zeroize")]
+impl zeroize::Zeroize for Output {
+    fn zeroize(&mut self) {
+        self.input_chaining_value.zeroize();
+        self.block.zeroize();
+        self.block_len.zeroize();
+        self.counter.zeroize();
+        self.flags.zeroize();
+    }
+}
+
+#[cfg(feature = "zeroize")]
+impl Drop for Output {
+    fn drop(&mut self) {
+        zeroize::Zeroize::zeroize(self);
+    }
 }
 
+#[cfg(feature = "zeroize")]
+impl zeroize::ZeroizeOnDrop for Output {}
+
 #[derive(Clone)]
 struct ChunkState {
     cv: CVWords,
@@ -168,7 +537,7 @@ impl ChunkState {
             if !input.is_empty() {
                 debug_assert_eq!(self.buf_len as usize, BLOCK_LEN);
                 let block_flags = self.flags | self.start_flag(); // borrowck
-                compress::compress_in_place(
+                Platform::detect().compress_in_place(
                     &mut self.cv,
                     &self.buf,
                     BLOCK_LEN as u8,
@@ -184,7 +553,7 @@ impl ChunkState {
         while input.len() > BLOCK_LEN {
             debug_assert_eq!(self.buf_len, 0);
             let block_flags = self.flags | self.start_flag(); // borrowck
-            compress::compress_in_place(
+            Platform::detect().compress_in_place(
                 &mut self.cv,
                 array_ref!(input, 0, BLOCK_LEN),
                 BLOCK_LEN as u8,
@@ -224,6 +593,28 @@ impl fmt::Debug for ChunkState {
     }
 }
 
+#[cfg(feature = "zeroize")]
+impl zeroize::Zeroize for ChunkState {
+    fn zeroize(&mut self) {
+        self.cv.zeroize();
+        self.chunk_counter.zeroize();
+        self.buf.zeroize();
+        self.buf_len.zeroize();
+        self.blocks_compressed.zeroize();
+        self.flags.zeroize();
+    }
+}
+
+#[cfg(feature = "zeroize")]
+impl Drop for ChunkState {
+    fn drop(&mut self) {
+        zeroize::Zeroize::zeroize(self);
+    }
+}
+
+#[cfg(feature = "zeroize")]
+impl zeroize::ZeroizeOnDrop for ChunkState {}
+
 // IMPLEMENTATION NOTE
 // ===================
 // The recursive function compress_subtree_wide(), implemented below, is the
@@ -247,6 +638,16 @@ pub enum IncrementCounter {
     No,
 }
 
+impl IncrementCounter {
+    #[inline]
+    fn yes(&self) -> bool {
+        match self {
+            IncrementCounter::Yes => true,
+            IncrementCounter::No => false,
+        }
+    }
+}
+
 // The largest power of two less than or equal to `n`, used for left_len()
 // immediately below, and also directly in Hasher::update().
 fn largest_power_of_two_leq(n: usize) -> usize {
@@ -263,8 +664,20 @@ fn left_len(content_len: usize) -> usize {
     largest_power_of_two_leq(full_chunks) * CHUNK_LEN
 }
 
-pub const MAX_SIMD_DEGREE: usize = 1;
-pub const MAX_SIMD_DEGREE_OR_2: usize = 2;
+// The portable hash_many() hashes compress::LANES inputs side by side, so
+// that's the widest degree compress_subtree_wide() can take advantage of. The
+// AVX2 implementation has the same degree, and SSE4.1 has half of it.
+pub const MAX_SIMD_DEGREE: usize = compress::LANES;
+
+// There are some places where we want a static size that's equal to the
+// MAX_SIMD_DEGREE, but also at least 2. Constant contexts aren't currently
+// allowed to use cmp::max, so we have to hardcode this additional constant
+// value. Get rid of this once cmp::max is a const fn.
+pub const MAX_SIMD_DEGREE_OR_2: usize = if MAX_SIMD_DEGREE > 2 {
+    MAX_SIMD_DEGREE
+} else {
+    2
+};
 
 // Use SIMD parallelism to hash up to MAX_SIMD_DEGREE chunks at the same time
 // on a single thread. Write out the chunk chaining values and return the
@@ -287,10 +700,11 @@ fn compress_chunks_parallel(
         chunks_array[chunks_array_len] = array_ref!(chunk, 0, CHUNK_LEN);
         chunks_array_len += 1;
     }
-    compress::hash_many(
+    Platform::detect().hash_many(
         &chunks_array[..chunks_array_len],
         key,
         chunk_counter,
+        IncrementCounter::Yes,
         flags,
         CHUNK_START,
         CHUNK_END,
@@ -337,10 +751,11 @@ fn compress_parents_parallel(
         parents_array[parents_array_len] = array_ref!(parent, 0, BLOCK_LEN);
         parents_array_len += 1;
     }
-    compress::hash_many(
+    Platform::detect().hash_many(
         &parents_array[..parents_array_len],
         key,
         0, // Parents always use counter 0.
+        IncrementCounter::No,
         flags | PARENT,
         0, // Parents have no start flags.
         0, // Parents have no end flags.
@@ -410,11 +825,19 @@ fn compress_subtree_wide<J: join::Join>(
     let (left_out, right_out) = cv_array.split_at_mut(degree * OUT_LEN);
 
     // Recurse! For update_rayon(), this is where we take advantage of RayonJoin and use multiple
-    // threads.
-    let (left_n, right_n) = J::join(
-        || compress_subtree_wide::<J>(left, key, chunk_counter, flags, left_out),
-        || compress_subtree_wide::<J>(right, key, right_chunk_counter, flags, right_out),
-    );
+    // threads. Subtrees shorter than J::MIN_LEN aren't worth the overhead of a join, so they
+    // recurse serially.
+    let (left_n, right_n) = if input.len() < J::MIN_LEN {
+        (
+            compress_subtree_wide::<J>(left, key, chunk_counter, flags, left_out),
+            compress_subtree_wide::<J>(right, key, right_chunk_counter, flags, right_out),
+        )
+    } else {
+        J::join(
+            || compress_subtree_wide::<J>(left, key, chunk_counter, flags, left_out),
+            || compress_subtree_wide::<J>(right, key, right_chunk_counter, flags, right_out),
+        )
+    };
 
     // The special case again. If simd_degree=1, then we'll have left_n=1 and
     // right_n=1. Rather than compressing them into a single output, return
@@ -497,6 +920,16 @@ pub fn hash(input: &[u8]) -> Hash {
     hash_all_at_once::<join::SerialJoin>(input, IV, 0).root_hash()
 }
 
+/// The default hash function, with the subtrees of a large input hashed by
+/// the given [`Join`] implementation.
+///
+/// This gives the same result as [`hash`]. `hash_with_join::<SerialJoin>` is
+/// equivalent to `hash`, and the [`join` module docs](join/index.html)
+/// describe how to plug in another executor.
+pub fn hash_with_join<J: Join>(input: &[u8]) -> Hash {
+    hash_all_at_once::<J>(input, IV, 0).root_hash()
+}
+
 /// The keyed hash function.
 ///
 /// This is suitable for use as a message authentication code, for example to
@@ -516,6 +949,25 @@ pub fn keyed_hash(key: &[u8; KEY_LEN], input: &[u8]) -> Hash {
     hash_all_at_once::<join::SerialJoin>(input, &key_words, KEYED_HASH).root_hash()
 }
 
+/// Verify a MAC produced by [`keyed_hash`](fn.keyed_hash.html).
+///
+/// The `tag` is compared against the keyed hash of `input` in constant time.
+/// Tags of any length other than 32 bytes are rejected.
+///
+/// This function is always single-threaded. To verify a MAC over a large or
+/// incremental input, use [`Hasher::new_keyed`] and compare the
+/// [`Hash`](struct.Hash.html) returned by [`Hasher::finalize`] with `==`.
+///
+/// [`Hasher::new_keyed`]: struct.Hasher.html#method.new_keyed
+/// [`Hasher::finalize`]: struct.Hasher.html#method.finalize
+pub fn verify_mac(key: &[u8; KEY_LEN], input: &[u8], tag: &[u8]) -> Result<(), MacError> {
+    if keyed_hash(key, input) == *tag {
+        Ok(())
+    } else {
+        Err(MacError(()))
+    }
+}
+
 /// The key derivation function.
 ///
 /// Given cryptographic key material of any length and a context string of any
@@ -592,6 +1044,9 @@ fn parent_node_output(
 /// When the `rayon` Cargo feature is enabled, the
 /// [`update_rayon`](#method.update_rayon) method is available for multithreaded
 /// hashing.
+/// Similarly, the `threads` Cargo feature enables the
+/// [`update_threads`](#method.update_threads) method, which uses standard
+/// library threads instead of Rayon.
 ///
 /// **Performance note:** The [`update`](#method.update) method can't take full
 /// advantage of SIMD optimizations if its input buffer is too small or oddly
@@ -603,18 +1058,18 @@ fn parent_node_output(
 /// ```
 /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
 /// // Hash an input incrementally.
-/// let mut hasher = blake3::Hasher::new();
+/// let mut hasher = blake3_balanced::Hasher::new();
 /// hasher.update(b"foo");
 /// hasher.update(b"bar");
 /// hasher.update(b"baz");
-/// assert_eq!(hasher.finalize(), blake3::hash(b"foobarbaz"));
+/// assert_eq!(hasher.finalize(), blake3_balanced::hash(b"foobarbaz"));
 ///
 /// // Extended output. OutputReader also implements Read and Seek.
 /// # #[cfg(feature = "std")] {
 /// let mut output = [0; 1000];
 /// let mut output_reader = hasher.finalize_xof();
 /// output_reader.fill(&mut output);
-/// assert_eq!(&output[..32], blake3::hash(b"foobarbaz").as_bytes());
+/// assert_eq!(&output[..32], blake3_balanced::hash(b"foobarbaz").as_bytes());
 /// # }
 /// # Ok(())
 /// # }
@@ -772,12 +1227,74 @@ impl Hasher {
         self.update_with_join::<join::SerialJoin>(input)
     }
 
+    /// Identical in effect to [`update`](#method.update), but using Rayon-based
+    /// multithreading internally.
+    ///
+    /// This method is gated by the `rayon` Cargo feature, which is disabled by
+    /// default.
+    ///
+    /// To get any performance benefit from multithreading, the input buffer
+    /// needs to be large. Subtrees shorter than
+    /// [`RAYON_DEFAULT_MIN_LEN`](join/constant.RAYON_DEFAULT_MIN_LEN.html) are
+    /// hashed serially, so shorter inputs use a single thread. Use
+    /// [`update_with_join`](#method.update_with_join) with a `RayonJoin` of
+    /// another `MIN_LEN` to change that threshold.
     #[cfg(feature = "rayon")]
     pub fn update_rayon(&mut self, input: &[u8]) -> &mut Self {
         self.update_with_join::<join::RayonJoin>(input)
     }
 
-    fn update_with_join<J: join::Join>(&mut self, mut input: &[u8]) -> &mut Self {
+    /// As [`update_rayon`](#method.update_rayon), but running on the given
+    /// Rayon thread pool instead of the global one.
+    ///
+    /// This method is gated by the `rayon` Cargo feature, which is disabled by
+    /// default.
+    #[cfg(feature = "rayon")]
+    pub fn update_rayon_in(&mut self, pool: &rayon::ThreadPool, input: &[u8]) -> &mut Self {
+        pool.install(|| {
+            self.update_rayon(input);
+        });
+        self
+    }
+
+    /// Identical in effect to [`update`](#method.update), but using up to
+    /// `max_threads` threads, including the calling one, to hash the input.
+    /// Unlike [`update_rayon`](#method.update_rayon), this uses scoped
+    /// standard library threads that are spawned for this call, so it
+    /// doesn't need a Rayon dependency. A `max_threads` of 0 or 1 means no
+    /// additional threads. [`std::thread::available_parallelism`] is a good
+    /// default.
+    ///
+    /// Spawning threads has a cost, so shorter inputs use fewer threads than
+    /// requested, and inputs shorter than about 256 KiB are hashed on the
+    /// calling thread. As with `update_rayon`, larger power-of-two input
+    /// buffers give the most parallelism.
+    ///
+    /// This method requires the `threads` Cargo feature, which is disabled by
+    /// default, and Rust 1.63 or later.
+    ///
+    /// [`std::thread::available_parallelism`]: https://doc.rust-lang.org/std/thread/fn.available_parallelism.html
+    #[cfg(feature = "threads")]
+    pub fn update_threads(&mut self, input: &[u8], max_threads: usize) -> &mut Self {
+        let threads = cmp::min(max_threads, input.len() / join::MIN_THREAD_LEN);
+        join::ThreadJoin::with_budget(cmp::max(threads, 1), || {
+            self.update_with_join::<join::ThreadJoin>(input);
+        });
+        self
+    }
+
+    /// Identical in effect to [`update`](#method.update), but with the
+    /// subtrees of a large input hashed by the given [`Join`] implementation.
+    /// `update_with_join::<SerialJoin>` is equivalent to `update`, and
+    /// [`update_rayon`](#method.update_rayon) is equivalent to
+    /// `update_with_join::<RayonJoin>`. See the
+    /// [`join` module docs](join/index.html) for how to plug in another
+    /// executor.
+    ///
+    /// As with [`update_rayon`](#method.update_rayon), only the subtrees of
+    /// a single call can be hashed in parallel, so larger power-of-two input
+    /// buffers give the most parallelism.
+    pub fn update_with_join<J: Join>(&mut self, mut input: &[u8]) -> &mut Self {
         // If we have some partial chunk bytes in the internal chunk_state, we
         // need to finish that chunk first.
         if self.chunk_state.len() > 0 {
@@ -955,10 +1472,153 @@ impl Hasher {
         self.final_output().root_hash()
     }
 
+    /// Finalize the hash state and return an [`OutputReader`], which can
+    /// supply any number of output bytes.
+    ///
+    /// This method is idempotent. Calling it twice will give the same result.
+    /// You can also add more input and finalize again.
+    ///
+    /// [`OutputReader`]: struct.OutputReader.html
+    pub fn finalize_xof(&self) -> OutputReader {
+        OutputReader::new(self.final_output())
+    }
+
     /// Return the total number of bytes hashed so far.
     pub fn count(&self) -> u64 {
         self.chunk_state.chunk_counter * CHUNK_LEN as u64 + self.chunk_state.len() as u64
     }
+
+    /// As [`update`](#method.update), but reading from a
+    /// [`std::io::Read`](https://doc.rust-lang.org/std/io/trait.Read.html)
+    /// implementation.
+    ///
+    /// [`Hasher`] implements
+    /// [`std::io::Write`](https://doc.rust-lang.org/std/io/trait.Write.html),
+    /// so it's possible to use [`std::io::copy`] to update a [`Hasher`] from
+    /// any reader. Unfortunately, this standard approach can limit
+    /// performance, because `copy` currently uses an internal 8 KiB buffer
+    /// that isn't big enough to take advantage of SIMD parallelism or the
+    /// subtree fast path in `update`. This method uses a larger buffer, a
+    /// multiple of the chunk length, instead. It retries reads that fail with
+    /// [`ErrorKind::Interrupted`], like `copy` does.
+    ///
+    /// This method requires the `std` Cargo feature, which is enabled by
+    /// default.
+    ///
+    /// [`Hasher`]: struct.Hasher.html
+    /// [`std::io::copy`]: https://doc.rust-lang.org/std/io/fn.copy.html
+    /// [`ErrorKind::Interrupted`]: https://doc.rust-lang.org/std/io/enum.ErrorKind.html#variant.Interrupted
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// # use std::fs::File;
+    /// # use std::io;
+    /// # fn main() -> io::Result<()> {
+    /// // Hash standard input.
+    /// let mut hasher = blake3_balanced::Hasher::new();
+    /// hasher.update_reader(std::io::stdin().lock())?;
+    /// println!("{}", hasher.finalize());
+    /// # Ok(())
+    /// # }
+    /// ```
+    #[cfg(feature = "std")]
+    pub fn update_reader(&mut self, reader: impl std::io::Read) -> std::io::Result<&mut Self> {
+        io::copy_wide(reader, self)?;
+        Ok(self)
+    }
+
+    /// As [`update`](#method.update), but using a memory map to read the
+    /// given file.
+    ///
+    /// Files smaller than 16 KiB, and special files like pipes that can't be
+    /// mapped, are read with [`update_reader`](#method.update_reader)
+    /// instead. If opening, mapping, or reading the file fails, this method
+    /// returns the error, and the `Hasher` may have absorbed part of the file.
+    ///
+    /// This method requires the `mmap` Cargo feature, which is disabled by
+    /// default.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// # use std::io;
+    /// # use std::path::Path;
+    /// # fn main() -> io::Result<()> {
+    /// let path = Path::new("file.dat");
+    /// let mut hasher = blake3_balanced::Hasher::new();
+    /// hasher.update_mmap(path)?;
+    /// println!("{}", hasher.finalize());
+    /// # Ok(())
+    /// # }
+    /// ```
+    #[cfg(feature = "mmap")]
+    pub fn update_mmap(&mut self, path: impl AsRef<std::path::Path>) -> std::io::Result<&mut Self> {
+        let file = std::fs::File::open(path.as_ref())?;
+        if let Some(mmap) = io::maybe_mmap_file(&file)? {
+            self.update(&mmap);
+        } else {
+            io::copy_wide(&file, self)?;
+        }
+        Ok(self)
+    }
+
+    /// As [`update_rayon`](#method.update_rayon), but using a memory map to
+    /// read the given file.
+    ///
+    /// Files smaller than 16 KiB, and special files like pipes that can't be
+    /// mapped, are read single-threaded with
+    /// [`update_reader`](#method.update_reader) instead. See
+    /// [`update_mmap`](#method.update_mmap) for the error behavior.
+    ///
+    /// This method requires both the `mmap` and `rayon` Cargo features, which
+    /// are disabled by default.
+    #[cfg(all(feature = "mmap", feature = "rayon"))]
+    pub fn update_mmap_rayon(
+        &mut self,
+        path: impl AsRef<std::path::Path>,
+    ) -> std::io::Result<&mut Self> {
+        let file = std::fs::File::open(path.as_ref())?;
+        if let Some(mmap) = io::maybe_mmap_file(&file)? {
+            self.update_rayon(&mmap);
+        } else {
+            io::copy_wide(&file, self)?;
+        }
+        Ok(self)
+    }
+
+    /// Serialize the state of the `Hasher`, so that hashing can resume later,
+    /// possibly in another process, with
+    /// [`from_state_bytes`](#method.from_state_bytes).
+    ///
+    /// The encoding starts with a version byte, and later versions of this
+    /// crate will keep reading it. It includes the key and the input of the
+    /// current chunk, so it's as secret as they are. With the `serde` Cargo
+    /// feature, `Hasher` implements `Serialize` and `Deserialize` with the
+    /// same bytes.
+    ///
+    /// ```
+    /// let mut hasher = blake3_balanced::Hasher::new();
+    /// hasher.update(b"foo");
+    /// let state = hasher.to_state_bytes();
+    ///
+    /// let mut resumed = blake3_balanced::Hasher::from_state_bytes(&state)?;
+    /// resumed.update(b"bar");
+    /// assert_eq!(resumed.finalize(), blake3_balanced::hash(b"foobar"));
+    /// # Ok::<(), blake3_balanced::StateError>(())
+    /// ```
+    pub fn to_state_bytes(&self) -> ArrayVec<u8, MAX_STATE_LEN> {
+        state::to_bytes(self)
+    }
+
+    /// Restore a `Hasher` from [`to_state_bytes`](#method.to_state_bytes).
+    ///
+    /// States that no sequence of updates could have produced, like a
+    /// truncated or corrupted CV stack, are rejected with a [`StateError`].
+    /// Corruption of the chaining values themselves can't be detected.
+    pub fn from_state_bytes(bytes: &[u8]) -> Result<Self, StateError> {
+        state::from_bytes(bytes)
+    }
 }
 
 // Don't derive(Debug), because the state may be secret.
@@ -977,8 +1637,196 @@ impl Default for Hasher {
     }
 }
 
+/// This wipes the key along with the rest of the state, so unlike
+/// [`reset`](Hasher::reset), it leaves the `Hasher` good for nothing but
+/// dropping. This requires the `zeroize` Cargo feature.
+#[cfg(feature = "zeroize")]
+impl zeroize::Zeroize for Hasher {
+    fn zeroize(&mut self) {
+        self.key.zeroize();
+        self.chunk_state.zeroize();
+        self.cv_stack.zeroize();
+        self.cv_stack_len.zeroize();
+    }
+}
+
+#[cfg(feature = "zeroize")]
+impl Drop for Hasher {
+    fn drop(&mut self) {
+        zeroize::Zeroize::zeroize(self);
+    }
+}
+
+#[cfg(feature = "zeroize")]
+impl zeroize::ZeroizeOnDrop for Hasher {}
+
+#[cfg(feature = "std")]
+impl std::io::Write for Hasher {
+    /// This is equivalent to [`update`](#method.update).
+    #[inline]
+    fn write(&mut self, input: &[u8]) -> std::io::Result<usize> {
+        self.update(input);
+        Ok(input.len())
+    }
+
+    #[inline]
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// An incremental reader for extended output, returned by
+/// [`Hasher::finalize_xof`](struct.Hasher.html#method.finalize_xof).
+///
+/// Outputs shorter than the default length of 32 bytes (256 bits) provide
+/// less security. An N-bit BLAKE3 output is intended to provide N bits of
+/// first and second preimage resistance and N/2 bits of collision
+/// resistance, for any N up to 256. Longer outputs don't provide any
+/// additional security.
+///
+/// Shorter BLAKE3 outputs are prefixes of longer ones. Explicitly
+/// requesting a short output is equivalent to truncating the default-length
+/// output. (Note that this is different between BLAKE2 and BLAKE3.)
+#[derive(Clone)]
+pub struct OutputReader {
+    inner: Output,
+    position_within_block: u8,
+}
+
+impl OutputReader {
+    fn new(inner: Output) -> Self {
+        Self {
+            inner,
+            position_within_block: 0,
+        }
+    }
+
+    /// Fill a buffer with output bytes and advance the position of the
+    /// `OutputReader`. This is equivalent to [`Read::read`], except that it
+    /// doesn't return a `Result`. Both methods always fill the entire buffer.
+    ///
+    /// Note that `OutputReader` doesn't buffer output bytes internally, so
+    /// calling `fill` repeatedly with a short-length or odd-length slice will
+    /// end up performing the same compression multiple times. If you're
+    /// reading output in a loop, prefer a slice length that's a multiple of
+    /// 64.
+    ///
+    /// The maximum output size of BLAKE3 is 2<sup>64</sup>-1 bytes. If you try
+    /// to extract more than that, for example by seeking near the end and
+    /// reading further, the behavior is unspecified.
+    ///
+    /// [`Read::read`]: https://doc.rust-lang.org/std/io/trait.Read.html#tymethod.read
+    pub fn fill(&mut self, mut buf: &mut [u8]) {
+        while !buf.is_empty() {
+            let block: [u8; BLOCK_LEN] = self.inner.root_output_block();
+            let output_bytes = &block[self.position_within_block as usize..];
+            let take = cmp::min(buf.len(), output_bytes.len());
+            buf[..take].copy_from_slice(&output_bytes[..take]);
+            buf = &mut buf[take..];
+            self.position_within_block += take as u8;
+            if self.position_within_block == BLOCK_LEN as u8 {
+                self.inner.counter += 1;
+                self.position_within_block = 0;
+            }
+        }
+    }
+
+    /// Return the current read position in the output stream. This is
+    /// equivalent to [`Seek::stream_position`], except that it doesn't return
+    /// a `Result`. The position of a new `OutputReader` starts at 0, and each
+    /// call to [`fill`] or [`Read::read`] moves the position forward by the
+    /// number of bytes read.
+    ///
+    /// [`Seek::stream_position`]: https://doc.rust-lang.org/std/io/trait.Seek.html#method.stream_position
+    /// [`fill`]: #method.fill
+    /// [`Read::read`]: https://doc.rust-lang.org/std/io/trait.Read.html#tymethod.read
+    pub fn position(&self) -> u64 {
+        self.inner.counter * BLOCK_LEN as u64 + self.position_within_block as u64
+    }
+
+    /// Seek to a new read position in the output stream. This is equivalent to
+    /// calling [`Seek::seek`] with [`SeekFrom::Start`], except that it doesn't
+    /// return a `Result`.
+    ///
+    /// [`Seek::seek`]: https://doc.rust-lang.org/std/io/trait.Seek.html#tymethod.seek
+    /// [`SeekFrom::Start`]: https://doc.rust-lang.org/std/io/enum.SeekFrom.html
+    pub fn set_position(&mut self, position: u64) {
+        self.position_within_block = (position % BLOCK_LEN as u64) as u8;
+        self.inner.counter = position / BLOCK_LEN as u64;
+    }
+}
+
+// Don't expose the state, because it may be secret.
+impl fmt::Debug for OutputReader {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("OutputReader")
+            .field("position", &self.position())
+            .finish()
+    }
+}
+
+/// This requires the `zeroize` Cargo feature.
+#[cfg(feature = "zeroize")]
+impl zeroize::Zeroize for OutputReader {
+    fn zeroize(&mut self) {
+        self.inner.zeroize();
+        self.position_within_block.zeroize();
+    }
+}
+
+#[cfg(feature = "zeroize")]
+impl Drop for OutputReader {
+    fn drop(&mut self) {
+        zeroize::Zeroize::zeroize(self);
+    }
+}
+
+#[cfg(feature = "zeroize")]
+impl zeroize::ZeroizeOnDrop for OutputReader {}
+
+#[cfg(feature = "std")]
+impl std::io::Read for OutputReader {
+    #[inline]
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.fill(buf);
+        Ok(buf.len())
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::io::Seek for OutputReader {
+    // The output stream is 2^64-1 bytes long, so SeekFrom::End counts
+    // backwards from u64::MAX. Positions outside of 0..=u64::MAX are errors,
+    // rather than being clamped or wrapped.
+    fn seek(&mut self, pos: std::io::SeekFrom) -> std::io::Result<u64> {
+        let max_position = u64::MAX as i128;
+        let target_position: i128 = match pos {
+            std::io::SeekFrom::Start(x) => x as i128,
+            std::io::SeekFrom::Current(x) => self.position() as i128 + x as i128,
+            std::io::SeekFrom::End(x) => max_position + x as i128,
+        };
+        if target_position < 0 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "seek before start",
+            ));
+        }
+        if target_position > max_position {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "seek past the end of the output stream",
+            ));
+        }
+        self.set_position(target_position as u64);
+        Ok(self.position())
+    }
+}
+
 #[cfg(test)]
 mod tests {
+    extern crate std;
+    use std::{format, string::ToString};
+
     /// asserts that all implementations have the same output
     fn assert_implementation_output(data: &[u8]) {
         let standard = {
@@ -1005,6 +1853,38 @@ mod tests {
         assert_eq!(reference, balanced);
     }
 
+    const MAX_XOF_LEN: usize = 4096;
+
+    /// asserts that all implementations have the same extended output
+    fn assert_implementation_xof(data: &[u8], len: usize) {
+        let standard = {
+            let mut hasher = ::blake3::Hasher::default();
+            hasher.update(data);
+            let mut out = [0; MAX_XOF_LEN];
+            hasher.finalize_xof().fill(&mut out[..len]);
+            out
+        };
+
+        let reference = {
+            let mut hasher = ::blake3_reference::Hasher::new();
+            hasher.update(data);
+            let mut out = [0; MAX_XOF_LEN];
+            hasher.finalize(&mut out[..len]);
+            out
+        };
+
+        let balanced = {
+            let mut hasher = super::Hasher::new();
+            hasher.update(data);
+            let mut out = [0; MAX_XOF_LEN];
+            hasher.finalize_xof().fill(&mut out[..len]);
+            out
+        };
+
+        assert_eq!(reference, standard);
+        assert_eq!(reference, balanced);
+    }
+
     #[test]
     fn small() {
         assert_implementation_output(b"small");
@@ -1033,4 +1913,351 @@ mod tests {
         let data = include_bytes!("../tests/data/fuzz_04");
         assert_implementation_output(data);
     }
+
+    #[test]
+    fn xof() {
+        for &len in &[0, 1, 31, 32, 33, 64, 65, 500, 2049] {
+            assert_implementation_xof(b"", len);
+            assert_implementation_xof(b"small", len);
+            assert_implementation_xof(&[0xab; 3 * super::CHUNK_LEN + 7], len);
+        }
+    }
+
+    #[test]
+    fn xof_seek() {
+        let mut hasher = super::Hasher::new();
+        hasher.update(b"seekable output");
+        let mut expected = [0; 300];
+        hasher.finalize_xof().fill(&mut expected);
+
+        let mut reader = hasher.finalize_xof();
+        for &start in &[0, 1, 63, 64, 65, 128, 299] {
+            reader.set_position(start as u64);
+            assert_eq!(reader.position(), start as u64);
+            let mut buf = [0; 300];
+            let buf = &mut buf[..300 - start];
+            reader.fill(buf);
+            assert_eq!(&expected[start..], &buf[..]);
+            assert_eq!(reader.position(), 300);
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn xof_read_seek() {
+        use std::io::{Read, Seek, SeekFrom};
+
+        let mut hasher = super::Hasher::new();
+        hasher.update(b"seekable output");
+        let mut expected = [0; 300];
+        hasher.finalize_xof().fill(&mut expected);
+
+        let mut reader = hasher.finalize_xof();
+        let mut buf = [0; 100];
+        assert_eq!(reader.seek(SeekFrom::Start(10)).unwrap(), 10);
+        reader.read_exact(&mut buf).unwrap();
+        assert_eq!(&expected[10..110], &buf[..]);
+        assert_eq!(reader.seek(SeekFrom::Current(-50)).unwrap(), 60);
+        reader.read_exact(&mut buf).unwrap();
+        assert_eq!(&expected[60..160], &buf[..]);
+
+        let end = u64::MAX;
+        assert_eq!(reader.seek(SeekFrom::End(0)).unwrap(), end);
+        assert_eq!(reader.seek(SeekFrom::End(-64)).unwrap(), end - 64);
+        assert!(reader.seek(SeekFrom::End(1)).is_err());
+        assert!(reader.seek(SeekFrom::Current(i64::MAX)).is_err());
+        // Large seeks backwards, within the output and then past its start.
+        assert_eq!(reader.seek(SeekFrom::End(-100)).unwrap(), end - 100);
+        let back = reader.seek(SeekFrom::Current(-i64::MAX)).unwrap();
+        assert_eq!(back, end - 100 - i64::MAX as u64);
+        assert_eq!(reader.position(), back);
+        assert!(reader.seek(SeekFrom::Current(i64::MIN)).is_err());
+        assert_eq!(reader.position(), back);
+        reader.set_position(5);
+        assert!(reader.seek(SeekFrom::Current(-6)).is_err());
+        assert_eq!(reader.position(), 5, "failed seeks don't move");
+    }
+
+    #[test]
+    fn hex() {
+        let balanced = super::hash(b"hex");
+        let standard = ::blake3::hash(b"hex");
+        assert_eq!(balanced.to_hex().as_str(), standard.to_hex().as_str());
+
+        let hex = balanced.to_hex();
+        let upper = hex.to_ascii_uppercase();
+        assert_eq!(super::Hash::from_hex(hex.as_str()).unwrap().as_bytes(), balanced.as_bytes());
+        assert_eq!(super::Hash::from_hex(&upper).unwrap().as_bytes(), balanced.as_bytes());
+        let parsed: super::Hash = hex.parse().unwrap();
+        assert_eq!(parsed.as_bytes(), balanced.as_bytes());
+
+        assert_eq!(format!("{}", balanced), hex.as_str());
+        assert_eq!(format!("{:?}", balanced), format!("Hash({:?})", hex.as_str()));
+
+        let bytes: [u8; super::OUT_LEN] = balanced.into();
+        assert_eq!(&bytes, super::Hash::from(bytes).as_bytes());
+        assert_eq!(super::Hash::from_slice(&bytes).unwrap().as_bytes(), &bytes);
+        assert!(super::Hash::from_slice(&bytes[..31]).is_err());
+        assert!(super::Hash::from_slice(&[0; 33]).is_err());
+    }
+
+    #[test]
+    fn hex_errors() {
+        let err = super::Hash::from_hex("ab").unwrap_err();
+        assert_eq!(err.to_string(), "expected 64 hex bytes, received 2");
+
+        let mut hex = *b"0000000000000000000000000000000000000000000000000000000000000000";
+        hex[63] = b'g';
+        let err = super::Hash::from_hex(hex).unwrap_err();
+        assert_eq!(err.to_string(), "invalid hex character: 'g'");
+        hex[63] = 0xff;
+        let err = super::Hash::from_hex(hex).unwrap_err();
+        assert_eq!(err.to_string(), "invalid hex character: 0xff");
+    }
+
+    #[test]
+    fn hash_eq() {
+        let a = super::hash(b"a");
+        let b = super::hash(b"b");
+        assert_eq!(a, super::hash(b"a"));
+        assert_ne!(a, b);
+        assert_eq!(a, *a.as_bytes());
+        assert_ne!(a, *b.as_bytes());
+        assert_eq!(a, a.as_bytes()[..]);
+        assert_ne!(a, a.as_bytes()[..31]);
+        assert_ne!(a, [0; 64][..]);
+
+        let set: std::collections::HashSet<super::Hash> = [a, b, a].iter().copied().collect();
+        assert_eq!(set.len(), 2);
+        assert!(set.contains(&super::hash(b"a")));
+    }
+
+    #[test]
+    fn verify_mac() {
+        let key = [42; super::KEY_LEN];
+        let tag = super::keyed_hash(&key, b"artifact");
+        assert_eq!(super::verify_mac(&key, b"artifact", tag.as_bytes()), Ok(()));
+        assert!(super::verify_mac(&key, b"artifacT", tag.as_bytes()).is_err());
+        assert!(super::verify_mac(&[0; super::KEY_LEN], b"artifact", tag.as_bytes()).is_err());
+        assert!(super::verify_mac(&key, b"artifact", &tag.as_bytes()[..16]).is_err());
+        assert_eq!(
+            super::verify_mac(&key, b"artifact", &[]).unwrap_err().to_string(),
+            "MAC verification failed"
+        );
+    }
+
+    // A reader that hands out odd-sized reads and interrupts every other call.
+    #[cfg(feature = "std")]
+    struct InterruptingReader<'a> {
+        data: &'a [u8],
+        interrupt: bool,
+    }
+
+    #[cfg(feature = "std")]
+    impl std::io::Read for InterruptingReader<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            self.interrupt = !self.interrupt;
+            if self.interrupt {
+                return Err(std::io::ErrorKind::Interrupted.into());
+            }
+            let take = std::cmp::min(self.data.len(), std::cmp::min(buf.len(), 3000));
+            buf[..take].copy_from_slice(&self.data[..take]);
+            self.data = &self.data[take..];
+            Ok(take)
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn update_reader() {
+        let data = include_bytes!("../benches/element-web-v1.10.10-vendors~init.js");
+        let expected = super::hash(data);
+
+        let mut hasher = super::Hasher::new();
+        hasher.update_reader(&data[..]).unwrap();
+        assert_eq!(hasher.finalize(), expected);
+
+        let reader = InterruptingReader {
+            data,
+            interrupt: false,
+        };
+        let mut hasher = super::Hasher::new();
+        hasher.update_reader(reader).unwrap();
+        assert_eq!(hasher.finalize(), expected);
+        assert_eq!(hasher.count(), data.len() as u64);
+
+        let mut hasher = super::Hasher::new();
+        std::io::copy(&mut &data[..], &mut hasher).unwrap();
+        assert_eq!(hasher.finalize(), expected);
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn update_reader_error() {
+        struct FailingReader;
+        impl std::io::Read for FailingReader {
+            fn read(&mut self, _: &mut [u8]) -> std::io::Result<usize> {
+                Err(std::io::ErrorKind::BrokenPipe.into())
+            }
+        }
+
+        let err = super::Hasher::new().update_reader(FailingReader).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::BrokenPipe);
+    }
+
+    #[test]
+    #[cfg(feature = "rayon")]
+    fn update_rayon() {
+        use super::join::RayonJoin;
+
+        let data = include_bytes!("../benches/element-web-v1.10.10-vendors~init.js");
+        let pool = rayon::ThreadPoolBuilder::new().num_threads(2).build().unwrap();
+        for &len in &[0, 1, super::CHUNK_LEN + 1, 300 * 1024, data.len()] {
+            let expected = ::blake3::hash(&data[..len]);
+
+            let mut hasher = super::Hasher::new();
+            hasher.update_rayon(&data[..len]);
+            assert_eq!(hasher.finalize(), *expected.as_bytes());
+
+            let mut hasher = super::Hasher::new();
+            hasher.update_with_join::<RayonJoin<0>>(&data[..len]);
+            assert_eq!(hasher.finalize(), *expected.as_bytes());
+
+            let mut hasher = super::Hasher::new();
+            hasher.update_with_join::<RayonJoin<{ 16 * super::CHUNK_LEN }>>(&data[..len]);
+            assert_eq!(hasher.finalize(), *expected.as_bytes());
+
+            let mut hasher = super::Hasher::new();
+            hasher.update_rayon_in(&pool, &data[..len]);
+            assert_eq!(hasher.finalize(), *expected.as_bytes());
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "threads")]
+    fn update_threads() {
+        let data = include_bytes!("../benches/element-web-v1.10.10-vendors~init.js");
+        let lens = [
+            0,
+            1,
+            super::CHUNK_LEN + 1,
+            2 * super::join::MIN_THREAD_LEN + 1000,
+            data.len(),
+        ];
+        for &len in &lens {
+            let expected = ::blake3::hash(&data[..len]);
+            for &max_threads in &[0, 1, 2, 3, 8] {
+                let mut hasher = super::Hasher::new();
+                hasher.update_threads(&data[..len], max_threads);
+                assert_eq!(hasher.finalize(), *expected.as_bytes());
+
+                // Start from an offset that isn't a power of two.
+                let split = core::cmp::min(len, 3 * super::CHUNK_LEN + 1);
+                let mut hasher = super::Hasher::new();
+                hasher.update(&data[..split]);
+                hasher.update_threads(&data[split..len], max_threads);
+                assert_eq!(hasher.finalize(), *expected.as_bytes());
+            }
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "threads")]
+    fn thread_join_budget() {
+        use super::join::{Join, ThreadJoin};
+        use std::thread;
+
+        // Without a budget, both sides run on the calling thread.
+        let (a, b) = ThreadJoin::join(|| thread::current().id(), || thread::current().id());
+        assert_eq!(a, thread::current().id());
+        assert_eq!(b, thread::current().id());
+
+        // With a budget of 2, the right side gets its own thread, and nested
+        // joins on either side are serial.
+        let ((a1, a2), (b1, b2)) = ThreadJoin::with_budget(2, || {
+            ThreadJoin::join(
+                || ThreadJoin::join(|| thread::current().id(), || thread::current().id()),
+                || ThreadJoin::join(|| thread::current().id(), || thread::current().id()),
+            )
+        });
+        assert_eq!(a1, thread::current().id());
+        assert_eq!(a2, a1);
+        assert_ne!(b1, a1);
+        assert_eq!(b2, b1);
+
+        // The budget is restored afterwards.
+        let (_, b) = ThreadJoin::join(|| (), || thread::current().id());
+        assert_eq!(b, thread::current().id());
+    }
+
+    #[test]
+    #[cfg(feature = "mmap")]
+    fn update_mmap() {
+        let data = include_bytes!("../benches/element-web-v1.10.10-vendors~init.js");
+        let dir = std::env::temp_dir();
+        for &len in &[0, 1000, 16 * super::CHUNK_LEN, data.len()] {
+            let path = dir.join(format!("blake3-balanced-mmap-{}-{}", std::process::id(), len));
+            std::fs::write(&path, &data[..len]).unwrap();
+            let expected = super::hash(&data[..len]);
+
+            let mut hasher = super::Hasher::new();
+            hasher.update_mmap(&path).unwrap();
+            assert_eq!(hasher.finalize(), expected);
+
+            #[cfg(feature = "rayon")]
+            {
+                let mut hasher = super::Hasher::new();
+                hasher.update_mmap_rayon(&path).unwrap();
+                assert_eq!(hasher.finalize(), expected);
+            }
+
+            std::fs::remove_file(&path).unwrap();
+        }
+
+        // Special files fall back to reading.
+        #[cfg(unix)]
+        {
+            let mut hasher = super::Hasher::new();
+            hasher.update_mmap("/dev/null").unwrap();
+            assert_eq!(hasher.finalize(), super::hash(b""));
+        }
+
+        let missing = dir.join("blake3-balanced-mmap-does-not-exist");
+        let err = super::Hasher::new().update_mmap(missing).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    #[cfg(feature = "zeroize")]
+    fn zeroize() {
+        use zeroize::Zeroize;
+
+        let key = [42; super::KEY_LEN];
+        let mut hasher = super::Hasher::new_keyed(&key);
+        hasher.update(&[1; 3 * super::CHUNK_LEN + 5]);
+        let mut reader = hasher.finalize_xof();
+        reader.fill(&mut [0; 100]);
+        hasher.zeroize();
+        assert_eq!(hasher.key, [0; 8]);
+        assert!(hasher.cv_stack.iter().all(|cv| cv == &[0; 32]));
+        assert_eq!(hasher.cv_stack_len, 0);
+        assert_eq!(hasher.chunk_state.cv, [0; 8]);
+        assert_eq!(hasher.chunk_state.buf, [0; super::BLOCK_LEN]);
+        assert_eq!(hasher.chunk_state.len(), 0);
+        assert_eq!(hasher.chunk_state.flags, 0);
+
+        reader.zeroize();
+        assert_eq!(reader.inner.input_chaining_value, [0; 8]);
+        assert_eq!(reader.inner.block, [0; super::BLOCK_LEN]);
+        assert_eq!(reader.position(), 0);
+
+        let mut key = super::Key::from(key);
+        assert_eq!(
+            super::keyed_hash(key.as_bytes(), b"foo"),
+            super::keyed_hash(&[42; super::KEY_LEN], b"foo")
+        );
+        assert_eq!(format!("{:?}", key), "Key(..)");
+        key.zeroize();
+        assert_eq!(key.as_bytes(), &[0; super::KEY_LEN]);
+    }
 }
@@ -1,3 +1,5 @@
+use crate::{compress, CVWords, IncrementCounter, BLOCK_LEN};
+
 macro_rules! array_ref {
     ($arr:expr, $idx:expr, $len:expr) => {{
         {
@@ -64,6 +66,30 @@ pub(crate) fn le_bytes_from_words_32(words: &[u32; 8]) -> [u8; 32] {
     out
 }
 
+#[inline(always)]
+pub(crate) fn le_bytes_from_words_64(words: &[u32; 16]) -> [u8; 64] {
+    let mut out = [0; 64];
+
+    word_to_bytes!(words, out, 0);
+    word_to_bytes!(words, out, 1);
+    word_to_bytes!(words, out, 2);
+    word_to_bytes!(words, out, 3);
+    word_to_bytes!(words, out, 4);
+    word_to_bytes!(words, out, 5);
+    word_to_bytes!(words, out, 6);
+    word_to_bytes!(words, out, 7);
+    word_to_bytes!(words, out, 8);
+    word_to_bytes!(words, out, 9);
+    word_to_bytes!(words, out, 10);
+    word_to_bytes!(words, out, 11);
+    word_to_bytes!(words, out, 12);
+    word_to_bytes!(words, out, 13);
+    word_to_bytes!(words, out, 14);
+    word_to_bytes!(words, out, 15);
+
+    out
+}
+
 #[inline(always)]
 pub(crate) fn words_from_le_bytes_32(bytes: &[u8; 32]) -> [u32; 8] {
     let mut out = [0; 8];
@@ -103,3 +129,298 @@ pub(crate) fn words_from_le_bytes_64(bytes: &[u8; 64]) -> [u32; 16] {
 
     out
 }
+
+// Compare two byte slices without branching on their contents, so that the
+// time taken doesn't reveal the position of the first differing byte. The
+// length check is allowed to short-circuit, because lengths aren't secret.
+// This is plain safe Rust rather than an optimization barrier, so it relies on
+// the compiler not turning the OR-accumulation back into an early exit, which
+// LLVM doesn't currently do. Keeping it out of line makes that less likely.
+#[inline(never)]
+pub(crate) fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    let mut diff = 0u8;
+    for (x, y) in a.iter().zip(b) {
+        diff |= x ^ y;
+    }
+    diff == 0
+}
+
+// The implementation of the compression function and hash_many() to use. The
+// portable implementation is always available. With the `intrinsics` feature
+// on x86_64, the SSE4.1 and AVX2 implementations are selected at runtime,
+// depending on what the CPU supports.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Platform {
+    Portable,
+    #[cfg(all(feature = "intrinsics", target_arch = "x86_64"))]
+    Sse41,
+    #[cfg(all(feature = "intrinsics", target_arch = "x86_64"))]
+    Avx2,
+}
+
+impl Platform {
+    // The fastest implementation supported by this CPU. The standard library
+    // caches the result of feature detection, so this is cheap to call for
+    // every compression.
+    #[inline]
+    pub(crate) fn detect() -> Self {
+        #[cfg(all(feature = "intrinsics", target_arch = "x86_64"))]
+        {
+            if let Some(avx2) = Self::avx2() {
+                return avx2;
+            }
+            if let Some(sse41) = Self::sse41() {
+                return sse41;
+            }
+        }
+        Platform::Portable
+    }
+
+    #[cfg(all(feature = "intrinsics", target_arch = "x86_64"))]
+    #[inline]
+    pub(crate) fn sse41() -> Option<Self> {
+        if is_x86_feature_detected!("sse4.1") {
+            Some(Platform::Sse41)
+        } else {
+            None
+        }
+    }
+
+    // The AVX2 implementation hands leftover inputs to the SSE4.1 one, so it
+    // needs both.
+    #[cfg(all(feature = "intrinsics", target_arch = "x86_64"))]
+    #[inline]
+    pub(crate) fn avx2() -> Option<Self> {
+        if is_x86_feature_detected!("avx2") && is_x86_feature_detected!("sse4.1") {
+            Some(Platform::Avx2)
+        } else {
+            None
+        }
+    }
+
+    #[cfg_attr(all(feature = "intrinsics", target_arch = "x86_64"), allow(unsafe_code))]
+    pub(crate) fn compress_in_place(
+        &self,
+        cv: &mut CVWords,
+        block: &[u8; BLOCK_LEN],
+        block_len: u8,
+        counter: u64,
+        flags: u8,
+    ) {
+        match self {
+            Platform::Portable => compress::compress_in_place(cv, block, block_len, counter, flags),
+            // Safe because detect() checked for SSE4.1 support. AVX2 has no
+            // advantage for a single block.
+            #[cfg(all(feature = "intrinsics", target_arch = "x86_64"))]
+            Platform::Sse41 | Platform::Avx2 => unsafe {
+                crate::sse41::compress_in_place(cv, block, block_len, counter, flags)
+            },
+        }
+    }
+
+    #[cfg_attr(all(feature = "intrinsics", target_arch = "x86_64"), allow(unsafe_code))]
+    pub(crate) fn compress_xof(
+        &self,
+        cv: &CVWords,
+        block: &[u8; BLOCK_LEN],
+        block_len: u8,
+        counter: u64,
+        flags: u8,
+    ) -> [u8; 64] {
+        match self {
+            Platform::Portable => compress::compress_xof(cv, block, block_len, counter, flags),
+            // Safe because detect() checked for SSE4.1 support.
+            #[cfg(all(feature = "intrinsics", target_arch = "x86_64"))]
+            Platform::Sse41 | Platform::Avx2 => unsafe {
+                crate::sse41::compress_xof(cv, block, block_len, counter, flags)
+            },
+        }
+    }
+
+    #[cfg_attr(all(feature = "intrinsics", target_arch = "x86_64"), allow(unsafe_code))]
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn hash_many<const N: usize>(
+        &self,
+        inputs: &[&[u8; N]],
+        key: &CVWords,
+        counter: u64,
+        increment_counter: IncrementCounter,
+        flags: u8,
+        flags_start: u8,
+        flags_end: u8,
+        out: &mut [u8],
+    ) {
+        match self {
+            Platform::Portable => compress::hash_many(
+                inputs,
+                key,
+                counter,
+                increment_counter,
+                flags,
+                flags_start,
+                flags_end,
+                out,
+            ),
+            // Safe because detect() checked for SSE4.1 support.
+            #[cfg(all(feature = "intrinsics", target_arch = "x86_64"))]
+            Platform::Sse41 => unsafe {
+                crate::sse41::hash_many(
+                    inputs,
+                    key,
+                    counter,
+                    increment_counter,
+                    flags,
+                    flags_start,
+                    flags_end,
+                    out,
+                )
+            },
+            // Safe because detect() checked for AVX2 and SSE4.1 support.
+            #[cfg(all(feature = "intrinsics", target_arch = "x86_64"))]
+            Platform::Avx2 => unsafe {
+                crate::avx2::hash_many(
+                    inputs,
+                    key,
+                    counter,
+                    increment_counter,
+                    flags,
+                    flags_start,
+                    flags_end,
+                    out,
+                )
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::convert::TryInto;
+    use crate::{CHUNK_END, CHUNK_LEN, CHUNK_START, IV, KEYED_HASH, OUT_LEN, PARENT};
+
+    const TEST_KEY: CVWords = [
+        0x03020100, 0x07060504, 0x0b0a0908, 0x0f0e0d0c, 0x13121110, 0x17161514, 0x1b1a1918,
+        0x1f1e1d1c,
+    ];
+
+    // Every implementation this CPU supports, including the portable one.
+    fn platforms() -> impl Iterator<Item = Platform> {
+        let platforms = [
+            Some(Platform::Portable),
+            #[cfg(all(feature = "intrinsics", target_arch = "x86_64"))]
+            Platform::sse41(),
+            #[cfg(all(feature = "intrinsics", target_arch = "x86_64"))]
+            Platform::avx2(),
+        ];
+        IntoIterator::into_iter(platforms).flatten()
+    }
+
+    fn paint_test_input(buf: &mut [u8]) {
+        for (i, b) in buf.iter_mut().enumerate() {
+            *b = (i % 251) as u8;
+        }
+    }
+
+    #[test]
+    fn compress() {
+        let mut block = [0; BLOCK_LEN];
+        paint_test_input(&mut block);
+        // Use a counter with set bits in both 32-bit halves.
+        let counter = (5u64 << 32) + 6;
+        for block_len in [0, 1, 31, 63, 64] {
+            let flags = CHUNK_START | CHUNK_END | KEYED_HASH;
+            let mut expected_cv = TEST_KEY;
+            compress::compress_in_place(&mut expected_cv, &block, block_len, counter, flags);
+            let expected_xof = compress::compress_xof(&TEST_KEY, &block, block_len, counter, flags);
+            for platform in platforms() {
+                let mut cv = TEST_KEY;
+                platform.compress_in_place(&mut cv, &block, block_len, counter, flags);
+                assert_eq!(expected_cv, cv, "{:?}", platform);
+                let xof = platform.compress_xof(&TEST_KEY, &block, block_len, counter, flags);
+                assert_eq!(&expected_xof[..], &xof[..], "{:?}", platform);
+            }
+        }
+    }
+
+    // Compare every platform's hash_many() against hash1() called in a loop,
+    // for both chunks and parents and for every number of inputs up to a few
+    // full groups plus a remainder.
+    fn check_hash_many<const N: usize>(
+        counter: u64,
+        increment_counter: IncrementCounter,
+        flags: u8,
+        flags_start: u8,
+        flags_end: u8,
+    ) {
+        const MAX_INPUTS: usize = 2 * crate::MAX_SIMD_DEGREE + 1;
+        let mut input_buf = [0; 2 * crate::MAX_SIMD_DEGREE * CHUNK_LEN + CHUNK_LEN];
+        paint_test_input(&mut input_buf);
+        let mut inputs = [&[0; N]; MAX_INPUTS];
+        for (input, input_chunk) in inputs.iter_mut().zip(input_buf.chunks_exact(N)) {
+            *input = input_chunk.try_into().unwrap();
+        }
+
+        let mut expected = [0; MAX_INPUTS * OUT_LEN];
+        for (i, (input, out)) in inputs.iter().zip(expected.chunks_exact_mut(OUT_LEN)).enumerate() {
+            let input_counter = if increment_counter.yes() {
+                counter + i as u64
+            } else {
+                counter
+            };
+            compress::hash1(
+                input,
+                &TEST_KEY,
+                input_counter,
+                flags,
+                flags_start,
+                flags_end,
+                array_ref_mut!(out, 0, OUT_LEN),
+            );
+        }
+
+        for platform in platforms() {
+            for num_inputs in 0..=MAX_INPUTS {
+                let mut out = [0; MAX_INPUTS * OUT_LEN];
+                platform.hash_many(
+                    &inputs[..num_inputs],
+                    &TEST_KEY,
+                    counter,
+                    increment_counter,
+                    flags,
+                    flags_start,
+                    flags_end,
+                    &mut out,
+                );
+                let len = num_inputs * OUT_LEN;
+                assert_eq!(&expected[..len], &out[..len], "{:?} {}", platform, num_inputs);
+                assert!(out[len..].iter().all(|&b| b == 0), "{:?} {}", platform, num_inputs);
+            }
+        }
+    }
+
+    #[test]
+    fn hash_many() {
+        // A counter just below a 32-bit boundary, so that incrementing it
+        // carries into the high word partway through a group.
+        let counter = (1u64 << 32) - 3;
+        check_hash_many::<CHUNK_LEN>(counter, IncrementCounter::Yes, 0, CHUNK_START, CHUNK_END);
+        check_hash_many::<CHUNK_LEN>(0, IncrementCounter::Yes, KEYED_HASH, CHUNK_START, CHUNK_END);
+        check_hash_many::<BLOCK_LEN>(0, IncrementCounter::No, PARENT, 0, 0);
+        check_hash_many::<BLOCK_LEN>(counter, IncrementCounter::No, KEYED_HASH | PARENT, 0, 0);
+    }
+
+    #[test]
+    fn detect() {
+        let platform = Platform::detect();
+        assert!(platforms().any(|p| p == platform));
+        let mut cv = *IV;
+        platform.compress_in_place(&mut cv, &[0; BLOCK_LEN], 0, 0, CHUNK_START | CHUNK_END);
+        let mut expected = *IV;
+        compress::compress_in_place(&mut expected, &[0; BLOCK_LEN], 0, 0, CHUNK_START | CHUNK_END);
+        assert_eq!(expected, cv);
+    }
+}
@@ -0,0 +1,428 @@
+// SSE4.1 implementations of the compression function and hash_many(), built
+// from core::arch intrinsics instead of the C and assembly that the blake3
+// crate compiles. Everything here is unsafe to call unless the CPU supports
+// SSE4.1, which platform::Platform checks at runtime before dispatching.
+#![allow(unsafe_code)]
+
+use core::arch::x86_64::*;
+
+use crate::compress::MSG_SCHEDULE;
+use crate::platform::{array_ref, array_ref_mut};
+use crate::{
+    counter_high, counter_low, CVBytes, CVWords, IncrementCounter, BLOCK_LEN, IV, OUT_LEN,
+};
+
+pub const DEGREE: usize = 4;
+
+#[inline(always)]
+unsafe fn loadu(src: *const u8) -> __m128i {
+    // This is an unaligned load, so the pointer cast is allowed.
+    _mm_loadu_si128(src as *const __m128i)
+}
+
+#[inline(always)]
+unsafe fn storeu(src: __m128i, dest: *mut u8) {
+    // This is an unaligned store, so the pointer cast is allowed.
+    _mm_storeu_si128(dest as *mut __m128i, src)
+}
+
+#[inline(always)]
+unsafe fn add(a: __m128i, b: __m128i) -> __m128i {
+    _mm_add_epi32(a, b)
+}
+
+#[inline(always)]
+unsafe fn xor(a: __m128i, b: __m128i) -> __m128i {
+    _mm_xor_si128(a, b)
+}
+
+#[inline(always)]
+unsafe fn set1(x: u32) -> __m128i {
+    _mm_set1_epi32(x as i32)
+}
+
+#[inline(always)]
+unsafe fn set4(a: u32, b: u32, c: u32, d: u32) -> __m128i {
+    _mm_setr_epi32(a as i32, b as i32, c as i32, d as i32)
+}
+
+// Rotations by whole bytes are a single byte shuffle. The others take two
+// shifts and an OR.
+#[inline(always)]
+unsafe fn rot16(a: __m128i) -> __m128i {
+    _mm_shuffle_epi8(
+        a,
+        _mm_setr_epi8(2, 3, 0, 1, 6, 7, 4, 5, 10, 11, 8, 9, 14, 15, 12, 13),
+    )
+}
+
+#[inline(always)]
+unsafe fn rot12(a: __m128i) -> __m128i {
+    _mm_or_si128(_mm_srli_epi32(a, 12), _mm_slli_epi32(a, 32 - 12))
+}
+
+#[inline(always)]
+unsafe fn rot8(a: __m128i) -> __m128i {
+    _mm_shuffle_epi8(
+        a,
+        _mm_setr_epi8(1, 2, 3, 0, 5, 6, 7, 4, 9, 10, 11, 8, 13, 14, 15, 12),
+    )
+}
+
+#[inline(always)]
+unsafe fn rot7(a: __m128i) -> __m128i {
+    _mm_or_si128(_mm_srli_epi32(a, 7), _mm_slli_epi32(a, 32 - 7))
+}
+
+// The first half of the G function, applied to all four columns at once.
+#[inline(always)]
+unsafe fn g1(rows: &mut [__m128i; 4], m: __m128i) {
+    rows[0] = add(add(rows[0], rows[1]), m);
+    rows[3] = rot16(xor(rows[3], rows[0]));
+    rows[2] = add(rows[2], rows[3]);
+    rows[1] = rot12(xor(rows[1], rows[2]));
+}
+
+// The second half of the G function.
+#[inline(always)]
+unsafe fn g2(rows: &mut [__m128i; 4], m: __m128i) {
+    rows[0] = add(add(rows[0], rows[1]), m);
+    rows[3] = rot8(xor(rows[3], rows[0]));
+    rows[2] = add(rows[2], rows[3]);
+    rows[1] = rot7(xor(rows[1], rows[2]));
+}
+
+// Rotate rows 1-3 so that the diagonals of the state line up as columns, and
+// lane i of each row holds a word of the i-th diagonal.
+#[inline(always)]
+unsafe fn diagonalize(rows: &mut [__m128i; 4]) {
+    rows[1] = _mm_shuffle_epi32(rows[1], 0b00_11_10_01);
+    rows[2] = _mm_shuffle_epi32(rows[2], 0b01_00_11_10);
+    rows[3] = _mm_shuffle_epi32(rows[3], 0b10_01_00_11);
+}
+
+#[inline(always)]
+unsafe fn undiagonalize(rows: &mut [__m128i; 4]) {
+    rows[1] = _mm_shuffle_epi32(rows[1], 0b10_01_00_11);
+    rows[2] = _mm_shuffle_epi32(rows[2], 0b01_00_11_10);
+    rows[3] = _mm_shuffle_epi32(rows[3], 0b00_11_10_01);
+}
+
+#[inline(always)]
+unsafe fn round(rows: &mut [__m128i; 4], m: &[u32; 16], round: usize) {
+    // Select the message schedule based on the round.
+    let s = MSG_SCHEDULE[round];
+
+    // Mix the columns.
+    g1(rows, set4(m[s[0]], m[s[2]], m[s[4]], m[s[6]]));
+    g2(rows, set4(m[s[1]], m[s[3]], m[s[5]], m[s[7]]));
+
+    // Mix the diagonals.
+    diagonalize(rows);
+    g1(rows, set4(m[s[8]], m[s[10]], m[s[12]], m[s[14]]));
+    g2(rows, set4(m[s[9]], m[s[11]], m[s[13]], m[s[15]]));
+    undiagonalize(rows);
+}
+
+#[inline(always)]
+unsafe fn compress_pre(
+    cv: &CVWords,
+    block: &[u8; BLOCK_LEN],
+    block_len: u8,
+    counter: u64,
+    flags: u8,
+) -> [__m128i; 4] {
+    let block_words = crate::platform::words_from_le_bytes_64(block);
+
+    let mut rows = [
+        loadu(cv.as_ptr() as *const u8),
+        loadu(cv.as_ptr().add(4) as *const u8),
+        set4(IV[0], IV[1], IV[2], IV[3]),
+        set4(
+            counter_low(counter),
+            counter_high(counter),
+            block_len as u32,
+            flags as u32,
+        ),
+    ];
+
+    round(&mut rows, &block_words, 0);
+    round(&mut rows, &block_words, 1);
+    round(&mut rows, &block_words, 2);
+    round(&mut rows, &block_words, 3);
+    round(&mut rows, &block_words, 4);
+    round(&mut rows, &block_words, 5);
+    round(&mut rows, &block_words, 6);
+
+    rows
+}
+
+#[target_feature(enable = "sse4.1")]
+pub unsafe fn compress_in_place(
+    cv: &mut CVWords,
+    block: &[u8; BLOCK_LEN],
+    block_len: u8,
+    counter: u64,
+    flags: u8,
+) {
+    let [row0, row1, row2, row3] = compress_pre(cv, block, block_len, counter, flags);
+    storeu(xor(row0, row2), cv.as_mut_ptr() as *mut u8);
+    storeu(xor(row1, row3), cv.as_mut_ptr().add(4) as *mut u8);
+}
+
+#[target_feature(enable = "sse4.1")]
+pub unsafe fn compress_xof(
+    cv: &CVWords,
+    block: &[u8; BLOCK_LEN],
+    block_len: u8,
+    counter: u64,
+    flags: u8,
+) -> [u8; 64] {
+    let [row0, row1, row2, row3] = compress_pre(cv, block, block_len, counter, flags);
+    let mut out = [0; 64];
+    storeu(xor(row0, row2), out.as_mut_ptr());
+    storeu(xor(row1, row3), out.as_mut_ptr().add(16));
+    storeu(
+        xor(row2, loadu(cv.as_ptr() as *const u8)),
+        out.as_mut_ptr().add(32),
+    );
+    storeu(
+        xor(row3, loadu(cv.as_ptr().add(4) as *const u8)),
+        out.as_mut_ptr().add(48),
+    );
+    out
+}
+
+// The transposed G function, where each vector holds one word of the state
+// for each of the DEGREE inputs.
+#[inline(always)]
+unsafe fn g(v: &mut [__m128i; 16], a: usize, b: usize, c: usize, d: usize, x: __m128i, y: __m128i) {
+    v[a] = add(add(v[a], v[b]), x);
+    v[d] = rot16(xor(v[d], v[a]));
+    v[c] = add(v[c], v[d]);
+    v[b] = rot12(xor(v[b], v[c]));
+    v[a] = add(add(v[a], v[b]), y);
+    v[d] = rot8(xor(v[d], v[a]));
+    v[c] = add(v[c], v[d]);
+    v[b] = rot7(xor(v[b], v[c]));
+}
+
+#[inline(always)]
+unsafe fn round_vecs(v: &mut [__m128i; 16], m: &[__m128i; 16], round: usize) {
+    // Select the message schedule based on the round.
+    let s = MSG_SCHEDULE[round];
+
+    // Mix the columns.
+    g(v, 0, 4, 8, 12, m[s[0]], m[s[1]]);
+    g(v, 1, 5, 9, 13, m[s[2]], m[s[3]]);
+    g(v, 2, 6, 10, 14, m[s[4]], m[s[5]]);
+    g(v, 3, 7, 11, 15, m[s[6]], m[s[7]]);
+
+    // Mix the diagonals.
+    g(v, 0, 5, 10, 15, m[s[8]], m[s[9]]);
+    g(v, 1, 6, 11, 12, m[s[10]], m[s[11]]);
+    g(v, 2, 7, 8, 13, m[s[12]], m[s[13]]);
+    g(v, 3, 4, 9, 14, m[s[14]], m[s[15]]);
+}
+
+// Transpose a 4x4 matrix of words, so that vecs[i][j] becomes vecs[j][i].
+#[inline(always)]
+unsafe fn transpose_vecs(vecs: &mut [__m128i; DEGREE]) {
+    let ab_01 = _mm_unpacklo_epi32(vecs[0], vecs[1]);
+    let ab_23 = _mm_unpackhi_epi32(vecs[0], vecs[1]);
+    let cd_01 = _mm_unpacklo_epi32(vecs[2], vecs[3]);
+    let cd_23 = _mm_unpackhi_epi32(vecs[2], vecs[3]);
+
+    vecs[0] = _mm_unpacklo_epi64(ab_01, cd_01);
+    vecs[1] = _mm_unpackhi_epi64(ab_01, cd_01);
+    vecs[2] = _mm_unpacklo_epi64(ab_23, cd_23);
+    vecs[3] = _mm_unpackhi_epi64(ab_23, cd_23);
+}
+
+// Load the block at `block_offset` from each input, transposed so that
+// msg[word] holds the given word of every input's block.
+#[inline(always)]
+unsafe fn transpose_msg_vecs<const N: usize>(
+    inputs: &[&[u8; N]; DEGREE],
+    block_offset: usize,
+) -> [__m128i; 16] {
+    let mut vecs = [_mm_setzero_si128(); 16];
+    for (quarter, quarter_vecs) in vecs.chunks_exact_mut(DEGREE).enumerate() {
+        for (input, vec) in inputs.iter().zip(quarter_vecs.iter_mut()) {
+            *vec = loadu(array_ref!(input, block_offset + 16 * quarter, 16).as_ptr());
+        }
+        transpose_vecs(array_ref_mut!(quarter_vecs, 0, DEGREE));
+    }
+    vecs
+}
+
+#[inline(always)]
+unsafe fn counter_vecs(counter: u64, increment_counter: IncrementCounter) -> (__m128i, __m128i) {
+    // When incrementing, each lane gets the counter plus its lane number.
+    let mask = if increment_counter.yes() { !0 } else { 0 };
+    (
+        set4(
+            counter_low(counter),
+            counter_low(counter + (mask & 1)),
+            counter_low(counter + (mask & 2)),
+            counter_low(counter + (mask & 3)),
+        ),
+        set4(
+            counter_high(counter),
+            counter_high(counter + (mask & 1)),
+            counter_high(counter + (mask & 2)),
+            counter_high(counter + (mask & 3)),
+        ),
+    )
+}
+
+#[target_feature(enable = "sse4.1")]
+#[allow(clippy::too_many_arguments)]
+unsafe fn hash4<const N: usize>(
+    inputs: &[&[u8; N]; DEGREE],
+    key: &CVWords,
+    counter: u64,
+    increment_counter: IncrementCounter,
+    flags: u8,
+    flags_start: u8,
+    flags_end: u8,
+    out: &mut [u8; DEGREE * OUT_LEN],
+) {
+    debug_assert_eq!(N % BLOCK_LEN, 0, "uneven blocks");
+    let mut h_vecs = [
+        set1(key[0]),
+        set1(key[1]),
+        set1(key[2]),
+        set1(key[3]),
+        set1(key[4]),
+        set1(key[5]),
+        set1(key[6]),
+        set1(key[7]),
+    ];
+    let (counter_low_vec, counter_high_vec) = counter_vecs(counter, increment_counter);
+    let mut block_flags = flags | flags_start;
+
+    for block_offset in (0..N).step_by(BLOCK_LEN) {
+        if block_offset + BLOCK_LEN == N {
+            block_flags |= flags_end;
+        }
+        let msg_vecs = transpose_msg_vecs(inputs, block_offset);
+        let mut v = [
+            h_vecs[0],
+            h_vecs[1],
+            h_vecs[2],
+            h_vecs[3],
+            h_vecs[4],
+            h_vecs[5],
+            h_vecs[6],
+            h_vecs[7],
+            set1(IV[0]),
+            set1(IV[1]),
+            set1(IV[2]),
+            set1(IV[3]),
+            counter_low_vec,
+            counter_high_vec,
+            set1(BLOCK_LEN as u32),
+            set1(block_flags as u32),
+        ];
+
+        round_vecs(&mut v, &msg_vecs, 0);
+        round_vecs(&mut v, &msg_vecs, 1);
+        round_vecs(&mut v, &msg_vecs, 2);
+        round_vecs(&mut v, &msg_vecs, 3);
+        round_vecs(&mut v, &msg_vecs, 4);
+        round_vecs(&mut v, &msg_vecs, 5);
+        round_vecs(&mut v, &msg_vecs, 6);
+
+        for i in 0..8 {
+            h_vecs[i] = xor(v[i], v[i + 8]);
+        }
+        block_flags = flags;
+    }
+
+    // Transpose the CVs back into one contiguous output per input.
+    transpose_vecs(array_ref_mut!(h_vecs, 0, DEGREE));
+    transpose_vecs(array_ref_mut!(h_vecs, DEGREE, DEGREE));
+    for (lane, output) in out.chunks_exact_mut(OUT_LEN).enumerate() {
+        storeu(h_vecs[lane], output.as_mut_ptr());
+        storeu(h_vecs[lane + DEGREE], output.as_mut_ptr().add(16));
+    }
+}
+
+#[target_feature(enable = "sse4.1")]
+unsafe fn hash1<const N: usize>(
+    input: &[u8; N],
+    key: &CVWords,
+    counter: u64,
+    flags: u8,
+    flags_start: u8,
+    flags_end: u8,
+    out: &mut CVBytes,
+) {
+    debug_assert_eq!(N % BLOCK_LEN, 0, "uneven blocks");
+    let mut cv = *key;
+    let mut block_flags = flags | flags_start;
+    let mut slice = &input[..];
+    while slice.len() >= BLOCK_LEN {
+        if slice.len() == BLOCK_LEN {
+            block_flags |= flags_end;
+        }
+        compress_in_place(
+            &mut cv,
+            array_ref!(slice, 0, BLOCK_LEN),
+            BLOCK_LEN as u8,
+            counter,
+            block_flags,
+        );
+        block_flags = flags;
+        slice = &slice[BLOCK_LEN..];
+    }
+    *out = crate::platform::le_bytes_from_words_32(&cv);
+}
+
+#[target_feature(enable = "sse4.1")]
+#[allow(clippy::too_many_arguments)]
+pub unsafe fn hash_many<const N: usize>(
+    inputs: &[&[u8; N]],
+    key: &CVWords,
+    mut counter: u64,
+    increment_counter: IncrementCounter,
+    flags: u8,
+    flags_start: u8,
+    flags_end: u8,
+    mut out: &mut [u8],
+) {
+    debug_assert!(out.len() >= inputs.len() * OUT_LEN, "out too short");
+    let mut groups = inputs.chunks_exact(DEGREE);
+    for group in &mut groups {
+        hash4(
+            array_ref!(group, 0, DEGREE),
+            key,
+            counter,
+            increment_counter,
+            flags,
+            flags_start,
+            flags_end,
+            array_ref_mut!(out, 0, DEGREE * OUT_LEN),
+        );
+        if increment_counter.yes() {
+            counter += DEGREE as u64;
+        }
+        out = &mut out[DEGREE * OUT_LEN..];
+    }
+
+    for (&input, output) in groups.remainder().iter().zip(out.chunks_exact_mut(OUT_LEN)) {
+        hash1(
+            input,
+            key,
+            counter,
+            flags,
+            flags_start,
+            flags_end,
+            array_ref_mut!(output, 0, OUT_LEN),
+        );
+        if increment_counter.yes() {
+            counter += 1;
+        }
+    }
+}
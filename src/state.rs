@@ -0,0 +1,298 @@
+//! The versioned binary encoding of a `Hasher`, for resuming it later.
+//!
+//! Version 1 of the encoding is, in order:
+//!
+//! - the version byte, 1
+//! - the flags of the hash mode, 1 byte
+//! - the key words, 32 bytes
+//! - the chaining value of the current chunk, 32 bytes
+//! - the chunk counter, 8 bytes
+//! - the number of blocks compressed in the current chunk, 1 byte
+//! - the length of the buffered block, 1 byte, and the block, 64 bytes
+//! - the number of CVs in the stack, 1 byte, and the CVs, 32 bytes each
+//!
+//! Words and integers are little-endian.
+
+use crate::platform::{array_ref, le_bytes_from_words_32, words_from_le_bytes_32};
+use crate::{
+    ChunkState, Hasher, StateError, StateErrorInner, BLOCK_LEN, CHUNK_LEN, DERIVE_KEY_MATERIAL, IV,
+    KEYED_HASH, MAX_DEPTH, MAX_STATE_LEN,
+};
+use arrayvec::ArrayVec;
+
+const VERSION: u8 = 1;
+
+// Everything but the CV stack.
+pub(crate) const HEADER_LEN: usize = 1 + 1 + 32 + 32 + 8 + 1 + 1 + BLOCK_LEN + 1;
+
+pub(crate) fn to_bytes(hasher: &Hasher) -> ArrayVec<u8, MAX_STATE_LEN> {
+    let chunk_state = &hasher.chunk_state;
+    let mut bytes = ArrayVec::new();
+    bytes.push(VERSION);
+    bytes.push(chunk_state.flags);
+    bytes.extend(le_bytes_from_words_32(&hasher.key));
+    bytes.extend(le_bytes_from_words_32(&chunk_state.cv));
+    bytes.extend(chunk_state.chunk_counter.to_le_bytes());
+    bytes.push(chunk_state.blocks_compressed);
+    bytes.push(chunk_state.buf_len);
+    bytes.extend(chunk_state.buf);
+    bytes.push(hasher.cv_stack_len as u8);
+    for cv in &hasher.cv_stack[..hasher.cv_stack_len] {
+        bytes.extend(*cv);
+    }
+    bytes
+}
+
+pub(crate) fn from_bytes(bytes: &[u8]) -> Result<Hasher, StateError> {
+    let invalid = |inner| Err(StateError(inner));
+    if bytes.len() < HEADER_LEN {
+        return invalid(StateErrorInner::InvalidLen(bytes.len()));
+    }
+    if bytes[0] != VERSION {
+        return invalid(StateErrorInner::UnknownVersion(bytes[0]));
+    }
+    let flags = bytes[1];
+    let key = words_from_le_bytes_32(array_ref!(bytes, 2, 32));
+    let cv = words_from_le_bytes_32(array_ref!(bytes, 34, 32));
+    let chunk_counter = u64::from_le_bytes(*array_ref!(bytes, 66, 8));
+    let blocks_compressed = bytes[74];
+    let buf_len = bytes[75];
+    let buf = *array_ref!(bytes, 76, BLOCK_LEN);
+    let cv_stack_len = bytes[HEADER_LEN - 1] as usize;
+    if bytes.len() != HEADER_LEN + 32 * cv_stack_len {
+        return invalid(StateErrorInner::InvalidLen(bytes.len()));
+    }
+
+    // Only the three modes of the Hasher constructors are possible, and the
+    // regular hash mode always uses the IV.
+    let valid_mode = match flags {
+        0 => key == *IV,
+        KEYED_HASH | DERIVE_KEY_MATERIAL => true,
+        _ => false,
+    };
+    if !valid_mode {
+        return invalid(StateErrorInner::InvalidMode);
+    }
+
+    // ChunkState::update() only compresses a block once more input follows
+    // it, so a chunk never ends on an empty buffer after the first block. The
+    // buffer is zero past its length, since that's the block padding.
+    let chunk_len = BLOCK_LEN * blocks_compressed as usize + buf_len as usize;
+    let valid_chunk = buf_len as usize <= BLOCK_LEN
+        && (blocks_compressed as usize) < CHUNK_LEN / BLOCK_LEN
+        && (blocks_compressed == 0 || buf_len > 0)
+        && (blocks_compressed > 0 || cv == key)
+        && buf[buf_len as usize..].iter().all(|&b| b == 0)
+        && chunk_counter
+            .checked_mul(CHUNK_LEN as u64)
+            .and_then(|len| len.checked_add(chunk_len as u64))
+            .is_some();
+    if !valid_chunk {
+        return invalid(StateErrorInner::InvalidChunk);
+    }
+    if cv_stack_len > MAX_DEPTH + 1 || !valid_stack_len(chunk_counter, chunk_len, cv_stack_len) {
+        return invalid(StateErrorInner::InvalidStack);
+    }
+
+    let mut hasher = Hasher::new_internal(&key, flags);
+    hasher.chunk_state = ChunkState {
+        cv,
+        chunk_counter,
+        buf,
+        buf_len,
+        blocks_compressed,
+        flags,
+    };
+    for i in 0..cv_stack_len {
+        hasher.stack_push(*array_ref!(bytes, HEADER_LEN + 32 * i, 32));
+    }
+    Ok(hasher)
+}
+
+// The number of CVs that Hasher::update_with_join() leaves in the stack. With
+// bytes in the current chunk, the stack is merged down to one CV per 1-bit of
+// the chunk counter, which is also the right answer for the very first chunk.
+// Without them, the last update ended with a subtree of some 2^k >= 2 chunks,
+// and the two halves of that subtree are left unmerged on top of the stack.
+fn valid_stack_len(chunk_counter: u64, chunk_len: usize, cv_stack_len: usize) -> bool {
+    if chunk_len > 0 || chunk_counter == 0 {
+        return cv_stack_len == chunk_counter.count_ones() as usize;
+    }
+    (1..64)
+        .map(|k| 1u64 << k)
+        .take_while(|&subtree_chunks| subtree_chunks <= chunk_counter)
+        .any(|subtree_chunks| {
+            chunk_counter & (subtree_chunks - 1) == 0
+                && cv_stack_len == (chunk_counter - subtree_chunks).count_ones() as usize + 2
+        })
+}
+
+#[cfg(feature = "serde")]
+impl serde::Serialize for Hasher {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_bytes(&to_bytes(self))
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for Hasher {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_bytes(StateVisitor)
+    }
+}
+
+#[cfg(feature = "serde")]
+struct StateVisitor;
+
+#[cfg(feature = "serde")]
+impl<'de> serde::de::Visitor<'de> for StateVisitor {
+    type Value = Hasher;
+
+    fn expecting(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        f.write_str("the state bytes of a BLAKE3 hasher")
+    }
+
+    fn visit_bytes<E: serde::de::Error>(self, bytes: &[u8]) -> Result<Hasher, E> {
+        from_bytes(bytes).map_err(E::custom)
+    }
+
+    // Formats without a byte string type, like JSON, use a sequence.
+    fn visit_seq<A: serde::de::SeqAccess<'de>>(self, mut seq: A) -> Result<Hasher, A::Error> {
+        let mut bytes = ArrayVec::<u8, MAX_STATE_LEN>::new();
+        while let Some(byte) = seq.next_element()? {
+            if bytes.try_push(byte).is_err() {
+                return Err(serde::de::Error::invalid_length(bytes.len() + 1, &self));
+            }
+        }
+        self.visit_bytes(&bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+    use super::*;
+    use crate::KEY_LEN;
+    use std::vec::Vec;
+
+    fn test_input(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i % 251) as u8).collect()
+    }
+
+    // Every kind of state that update() leaves behind: partial and full
+    // chunks, and the unmerged halves of subtrees of different sizes.
+    const SPLITS: &[usize] = &[
+        0,
+        1,
+        BLOCK_LEN,
+        BLOCK_LEN + 1,
+        CHUNK_LEN - 1,
+        CHUNK_LEN,
+        CHUNK_LEN + 1,
+        2 * CHUNK_LEN,
+        3 * CHUNK_LEN,
+        5 * CHUNK_LEN + 100,
+        8 * CHUNK_LEN,
+        12 * CHUNK_LEN,
+        31 * CHUNK_LEN + 7,
+    ];
+
+    #[test]
+    fn resume() {
+        let input = test_input(40 * CHUNK_LEN);
+        let key = [42; KEY_LEN];
+        let hashers = [
+            Hasher::new(),
+            Hasher::new_keyed(&key),
+            Hasher::new_derive_key("resume"),
+        ];
+        let expected = [
+            blake3::hash(&input),
+            blake3::keyed_hash(&key, &input),
+            blake3::Hasher::new_derive_key("resume")
+                .update(&input)
+                .finalize(),
+        ];
+        for (hasher, expected) in hashers.iter().zip(&expected) {
+            for &split in SPLITS {
+                for &piece_len in &[CHUNK_LEN / 2, 4 * CHUNK_LEN, input.len()] {
+                    let mut hasher = hasher.clone();
+                    for piece in input[..split].chunks(piece_len) {
+                        hasher.update(piece);
+                    }
+                    let state = hasher.to_state_bytes();
+                    let mut resumed = Hasher::from_state_bytes(&state).unwrap();
+                    assert_eq!(resumed.to_state_bytes(), state);
+                    assert_eq!(resumed.finalize(), hasher.finalize());
+                    resumed.update(&input[split..]);
+                    assert_eq!(resumed.finalize(), *expected.as_bytes(), "split {}", split);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn invalid_states() {
+        let mut hasher = Hasher::new();
+        hasher.update(&test_input(5 * CHUNK_LEN + 100));
+        let state = hasher.to_state_bytes();
+        assert_eq!(state.len(), HEADER_LEN + 2 * 32);
+        let check = |i: usize, byte: u8| {
+            let mut bad = state.clone();
+            bad[i] = byte;
+            Hasher::from_state_bytes(&bad).unwrap_err()
+        };
+        // The version, the mode, and a hash mode key that isn't the IV.
+        check(0, 2);
+        check(1, 1);
+        check(1, KEYED_HASH | DERIVE_KEY_MATERIAL);
+        check(2, state[2] ^ 1);
+        // Too many blocks, a longer block than BLOCK_LEN, a chunk that ends on
+        // a compressed block, and padding that isn't zero.
+        check(74, 16);
+        check(75, BLOCK_LEN as u8 + 1);
+        check(75, 0);
+        check(HEADER_LEN - 2, 1);
+        // A chunk counter that doesn't match the stack, and one that
+        // overflows the length.
+        check(66, 4);
+        check(73, 0x40);
+
+        assert!(Hasher::from_state_bytes(&[]).is_err());
+        assert!(Hasher::from_state_bytes(&state[..state.len() - 1]).is_err());
+        let mut long = state.to_vec();
+        long.push(0);
+        assert!(Hasher::from_state_bytes(&long).is_err());
+        // A stack length that matches the length of the state but not the
+        // chunk counter.
+        let mut short_stack = state[..state.len() - 32].to_vec();
+        short_stack[HEADER_LEN - 1] = 1;
+        assert!(Hasher::from_state_bytes(&short_stack).is_err());
+
+        // After a whole subtree, the stack can hold two unmerged CVs more than
+        // the chunk counter has 1-bits, but not one.
+        let mut hasher = Hasher::new();
+        hasher.update(&test_input(8 * CHUNK_LEN));
+        let state = hasher.to_state_bytes();
+        assert_eq!(state.len(), HEADER_LEN + 2 * 32);
+        let mut short_stack = state[..state.len() - 32].to_vec();
+        short_stack[HEADER_LEN - 1] = 1;
+        assert!(Hasher::from_state_bytes(&short_stack).is_err());
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serde() {
+        let input = test_input(5 * CHUNK_LEN + 100);
+        let mut hasher = Hasher::new_keyed(&[7; KEY_LEN]);
+        hasher.update(&input[..3 * CHUNK_LEN + 1]);
+        let json = serde_json::to_string(&hasher).unwrap();
+        let mut resumed: Hasher = serde_json::from_str(&json).unwrap();
+        resumed.update(&input[3 * CHUNK_LEN + 1..]);
+        assert_eq!(
+            resumed.finalize(),
+            *blake3::keyed_hash(&[7; KEY_LEN], &input).as_bytes()
+        );
+        assert!(serde_json::from_str::<Hasher>("[1, 2, 3]").is_err());
+    }
+}
@@ -0,0 +1,207 @@
+//! Runs the `b3sum` binary and compares its output to what the real `b3sum`
+//! prints, using the `blake3` crate to produce the expected hashes.
+#![cfg(feature = "bin")]
+
+use std::io::Write;
+use std::path::PathBuf;
+use std::process::{Command, Output, Stdio};
+
+fn b3sum(args: &[&str], stdin: &[u8]) -> Output {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_b3sum"))
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    // b3sum exits without reading stdin when it rejects its arguments.
+    match child.stdin.take().unwrap().write_all(stdin) {
+        Err(e) if e.kind() == std::io::ErrorKind::BrokenPipe => {}
+        result => result.unwrap(),
+    }
+    child.wait_with_output().unwrap()
+}
+
+fn stdout(output: Output) -> String {
+    assert!(output.status.success(), "{:?}", output);
+    String::from_utf8(output.stdout).unwrap()
+}
+
+fn temp_file(name: &str, contents: &[u8]) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("blake3-balanced-b3sum-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join(name);
+    std::fs::write(&path, contents).unwrap();
+    path
+}
+
+#[test]
+fn hash_files_and_stdin() {
+    let large = vec![0xab; 100_000];
+    let a = temp_file("a", b"foo");
+    let b = temp_file("b", &large);
+    let (a, b) = (a.to_str().unwrap(), b.to_str().unwrap());
+
+    let expected = format!(
+        "{}  {}\n{}  {}\n{}  -\n",
+        blake3::hash(b"foo").to_hex(),
+        a,
+        blake3::hash(&large).to_hex(),
+        b,
+        blake3::hash(b"bar").to_hex(),
+    );
+    assert_eq!(stdout(b3sum(&[a, b, "-"], b"bar")), expected);
+    assert_eq!(stdout(b3sum(&["--no-mmap", a, b, "-"], b"bar")), expected);
+    assert_eq!(
+        stdout(b3sum(&[], b"bar")),
+        format!("{}  -\n", blake3::hash(b"bar").to_hex())
+    );
+}
+
+#[test]
+fn length_raw_and_no_names() {
+    let mut xof = [0; 100];
+    blake3::Hasher::new()
+        .update(b"foo")
+        .finalize_xof()
+        .fill(&mut xof);
+    let hex: String = xof.iter().map(|b| format!("{:02x}", b)).collect();
+
+    assert_eq!(stdout(b3sum(&["--length", "100"], b"foo")), format!("{}  -\n", hex));
+    assert_eq!(stdout(b3sum(&["-l", "3", "--no-names"], b"foo")), format!("{}\n", &hex[..6]));
+    assert_eq!(stdout(b3sum(&["--length=0"], b"foo")), "  -\n");
+
+    let raw = b3sum(&["--raw", "--length=100"], b"foo");
+    assert!(raw.status.success());
+    assert_eq!(raw.stdout, &xof[..]);
+}
+
+#[test]
+fn keyed_and_derive_key() {
+    let key = [42; 32];
+    let file = temp_file("keyed", b"foo");
+    let file = file.to_str().unwrap();
+    assert_eq!(
+        stdout(b3sum(&["--keyed", file], &key)),
+        format!("{}  {}\n", blake3::keyed_hash(&key, b"foo").to_hex(), file)
+    );
+    assert!(!b3sum(&["--keyed", file], &key[..31]).status.success());
+    assert!(!b3sum(&["--keyed", file], &[0; 33]).status.success());
+    assert!(!b3sum(&["--keyed"], &key).status.success());
+
+    let mut hasher = blake3::Hasher::new_derive_key("context");
+    hasher.update(b"foo");
+    assert_eq!(
+        stdout(b3sum(&["--derive-key", "context"], b"foo")),
+        format!("{}  -\n", hasher.finalize().to_hex())
+    );
+    assert!(!b3sum(&["--keyed", "--derive-key", "context"], &key).status.success());
+}
+
+#[test]
+fn escaped_names() {
+    let file = temp_file("back\\slash\nnewline", b"foo");
+    let name = file.to_str().unwrap();
+    let escaped = name.replace('\\', "\\\\").replace('\n', "\\n");
+    assert_eq!(
+        stdout(b3sum(&[name], b"")),
+        format!("\\{}  {}\n", blake3::hash(b"foo").to_hex(), escaped)
+    );
+    // Without names, there's nothing to escape.
+    assert_eq!(
+        stdout(b3sum(&["--no-names", name], b"")),
+        format!("{}\n", blake3::hash(b"foo").to_hex())
+    );
+}
+
+#[test]
+fn errors() {
+    let file = temp_file("errors", b"foo");
+    let file = file.to_str().unwrap();
+    let missing = "/this/file/does/not/exist";
+
+    let output = b3sum(&[missing, file], b"");
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        format!("{}  {}\n", blake3::hash(b"foo").to_hex(), file)
+    );
+    assert!(String::from_utf8(output.stderr).unwrap().starts_with("b3sum: /this/file"));
+
+    assert_eq!(b3sum(&["--bogus"], b"").status.code(), Some(2));
+    assert_eq!(b3sum(&["--length", "x"], b"").status.code(), Some(2));
+    assert_eq!(b3sum(&["--raw", file, file], b"").status.code(), Some(2));
+}
+
+#[test]
+fn check() {
+    let a = temp_file("check-a", b"foo");
+    let b = temp_file("check\\b", b"bar");
+    let (a, b) = (a.to_str().unwrap(), b.to_str().unwrap());
+    let manifest = stdout(b3sum(&[a, b], b""));
+    let escaped_b = format!("\\{}", b.replace('\\', "\\\\"));
+
+    let output = stdout(b3sum(&["--check"], manifest.as_bytes()));
+    assert_eq!(output, format!("{}: OK\n{}: OK\n", a, escaped_b));
+    assert_eq!(stdout(b3sum(&["-c", "--quiet"], manifest.as_bytes())), "");
+
+    // A checkfile can also be named on the command line, and CRLF line
+    // endings are accepted.
+    let crlf = manifest.replace('\n', "\r\n");
+    let checkfile = temp_file("checkfile", crlf.as_bytes());
+    let output = stdout(b3sum(&["-c", checkfile.to_str().unwrap()], b""));
+    assert_eq!(output, format!("{}: OK\n{}: OK\n", a, escaped_b));
+}
+
+#[test]
+fn check_failures() {
+    let a = temp_file("check-fail-a", b"foo");
+    let a = a.to_str().unwrap();
+    let missing = "/this/file/does/not/exist";
+    let manifest = format!(
+        "{hash}  {a}\n{wrong}  {a}\nnot a checksum line\n{hash}  {missing}\n",
+        hash = blake3::hash(b"foo").to_hex(),
+        wrong = blake3::hash(b"bar").to_hex(),
+        a = a,
+        missing = missing,
+    );
+
+    let output = b3sum(&["--check", "--quiet"], manifest.as_bytes());
+    assert_eq!(output.status.code(), Some(1));
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let mut lines = stdout.lines();
+    assert_eq!(lines.next().unwrap(), format!("{}: FAILED", a));
+    assert!(lines.next().unwrap().starts_with(&format!("{}: FAILED (", missing)));
+    assert_eq!(lines.next(), None);
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert_eq!(
+        stderr,
+        "b3sum: short line\nb3sum: WARNING: 3 computed checksums did NOT match\n"
+    );
+
+    // A line that isn't UTF-8 fails on its own, and the rest are checked.
+    let mut checkfile = format!("{}  {}\n", blake3::hash(b"foo").to_hex(), a).into_bytes();
+    checkfile.extend_from_slice(b"\xff\xfe  bad\n");
+    checkfile.extend_from_slice(format!("{}  {}\n", blake3::hash(b"foo").to_hex(), a).as_bytes());
+    let output = b3sum(&["--check"], &checkfile);
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        format!("{a}: OK\n{a}: OK\n", a = a)
+    );
+    assert_eq!(
+        String::from_utf8(output.stderr).unwrap(),
+        "b3sum: invalid UTF-8\nb3sum: WARNING: 1 computed checksum did NOT match\n"
+    );
+
+    // A `-` line can't be checked when stdin is the checkfile.
+    let stdin_line = format!("{}  -\n", blake3::hash(b"").to_hex());
+    let output = b3sum(&["--check", "-"], stdin_line.as_bytes());
+    assert_eq!(output.status.code(), Some(1));
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(stdout.starts_with("-: FAILED ("), "{:?}", stdout);
+
+    assert_eq!(b3sum(&["--check", missing], b"").status.code(), Some(1));
+    assert_eq!(b3sum(&["--check", "--raw"], b"").status.code(), Some(2));
+    assert_eq!(b3sum(&["--quiet"], b"").status.code(), Some(2));
+}